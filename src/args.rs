@@ -1,5 +1,40 @@
 use clap::Parser;
 
+use crate::logging::LogFormat;
+
+/// Options for the log output, shared by all binaries.
+#[derive(clap::Args, Debug)]
+pub struct LogArgs {
+    /// Which messages to log: a level, optionally followed by levels for
+    /// single modules, e.g. `warn,firelight::renderer=debug`.
+    #[clap(long, default_value = "info")]
+    pub log_level: String,
+
+    /// How to write log messages. `journald` falls back to text on
+    /// stderr if the journal isn't available.
+    #[clap(long, arg_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+/// Options for the audio input of the audio-reactive effects.
+#[derive(clap::Args, Debug)]
+pub struct AudioArgs {
+    /// Analyze audio from `alsa:DEVICE`, `file:PATH` or `fifo:PATH`. Files
+    /// and FIFOs contain either a WAV file or raw 16 bit little-endian
+    /// samples. A FIFO is reopened after every writer, so that e.g.
+    /// `cat song.wav > PATH` can be repeated.
+    #[clap(long)]
+    pub audio_input: Option<String>,
+
+    /// Sample rate of ALSA devices and of raw samples.
+    #[clap(long, default_value = "44100")]
+    pub audio_rate: u32,
+
+    /// Number of interleaved channels of ALSA devices and of raw samples.
+    #[clap(long, default_value = "2")]
+    pub audio_channels: u16,
+}
+
 /// Provides a control interface for WS2811 LED Light strips.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -8,21 +43,87 @@ pub struct DaemonArgs {
     #[clap(short, long)]
     pub unix_socket: String,
 
+    /// A TOML file with further settings, which is read again on SIGHUP.
+    /// The options below take precedence over the values in the file.
+    #[clap(long)]
+    pub config: Option<String>,
+
     /// The PWM channel to which the LED strip is connected. Usually 0 or 1.
     #[clap(short, long)]
-    pub channel: usize,
+    pub channel: Option<usize>,
 
     /// The DMA offset number.
     #[clap(short, long)]
-    pub dma: i32,
+    pub dma: Option<i32>,
 
     /// The pin to which the LED strip is attached
     #[clap(short, long)]
-    pub pin: i32,
+    pub pin: Option<i32>,
 
     /// How many LEDs the strip contains.
     #[clap(short, long)]
-    pub leds_count: usize,
+    pub leds_count: Option<usize>,
+
+    /// A unique identifier for this lamp, added to all log messages.
+    #[clap(short, long, default_value="firelight-lamp")]
+    pub instance_name: String,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+/// A stand-in for the daemon that draws received frames to the terminal
+/// instead of sending them to an LED strip.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct DebugDaemonArgs {
+    /// Path to the listening socket of the daemon.
+    #[clap(short, long)]
+    pub unix_socket: String,
+
+    /// The logical arrangement of the strip into vertical strands.
+    /// If not given, every frame is drawn as a single strand.
+    #[clap(short, long, multiple_occurrences = false, multiple_values = true, use_delimiter= true)]
+    pub strands: Vec<usize>,
+
+    /// Don't draw frames to the terminal, only print their size.
+    #[clap(short, long)]
+    pub quiet: bool,
+
+    /// Write every received frame as a numbered PPM image into this directory.
+    #[clap(long)]
+    pub dump_ppm: Option<String>,
+
+    /// Size in pixels of a single LED in the dumped images.
+    #[clap(long, default_value = "8")]
+    pub scale: usize,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+/// A shell for interactive debugging and scripted demos.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct ShellArgs {
+    /// Path to the listening socket of the daemon.
+    #[clap(short, long)]
+    pub daemon_socket: String,
+
+    /// The logical arrangement of the strip into vertical strands.
+    #[clap(short, long, multiple_occurrences = false, multiple_values = true, use_delimiter= true)]
+    pub strands: Vec<usize>,
+
+    /// Execute the commands from this file instead of starting an
+    /// interactive session. Use `-` to read them from stdin.
+    #[clap(short = 'f', long)]
+    pub script: Option<String>,
+
+    #[clap(flatten)]
+    pub audio: AudioArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
 }
 
 /// Starts a REST Api and web interface to control
@@ -45,5 +146,68 @@ pub struct ServerArgs {
     /// The logical arrangement of the strip into vertical strands.
     #[clap(short, long, multiple_occurrences = false, multiple_values = true, use_delimiter= true)]
     pub strands: Vec<usize>,
+
+    /// File in which presets are stored. If not given, presets
+    /// are lost when the server restarts.
+    #[clap(long)]
+    pub presets_file: Option<String>,
+
+    /// File in which schedule rules are stored. If not given, rules
+    /// are lost when the server restarts.
+    #[clap(long)]
+    pub schedule_file: Option<String>,
+
+    /// Latitude of the lamp in degrees, positive towards north.
+    /// Needed for rules relative to sunrise or sunset.
+    #[clap(long, allow_hyphen_values = true, requires = "longitude")]
+    pub latitude: Option<f64>,
+
+    /// Longitude of the lamp in degrees, positive towards east.
+    #[clap(long, allow_hyphen_values = true, requires = "latitude")]
+    pub longitude: Option<f64>,
+
+    /// TOML file with authentication and TLS settings.
+    #[clap(long)]
+    pub config: Option<String>,
+
+    /// Receive E1.31 (sACN) on this address, usually `0.0.0.0:5568`.
+    /// While packets arrive, they are shown instead of the rendered frames.
+    #[clap(long)]
+    pub e131_bind: Option<String>,
+
+    /// Receive Art-Net on this address, usually `0.0.0.0:6454`.
+    #[clap(long)]
+    pub artnet_bind: Option<String>,
+
+    /// The universe in which the channels of the strip start. Defaults to
+    /// the first universe of each protocol, 1 for E1.31 and 0 for Art-Net.
+    #[clap(long)]
+    pub dmx_universe: Option<u16>,
+
+    /// The channel of the first universe at which the strip starts.
+    #[clap(long, default_value = "1")]
+    pub dmx_channel: u16,
+
+    /// Start every strand at the first channel of a new universe,
+    /// instead of packing the LEDs of all strands.
+    #[clap(long)]
+    pub dmx_universe_per_strand: bool,
+
+    /// Receive the WLED realtime protocols WARLS, DRGB, DRGBW and DNRGB
+    /// on this address, usually `0.0.0.0:21324`.
+    #[clap(long)]
+    pub wled_udp_bind: Option<String>,
+
+    /// Accept Open Pixel Control clients on this address, usually
+    /// `0.0.0.0:7890`. Channel 0 addresses the whole strip, channel
+    /// `n` the `n`-th strand.
+    #[clap(long)]
+    pub opc_bind: Option<String>,
+
+    #[clap(flatten)]
+    pub audio: AudioArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
 }
 
@@ -0,0 +1,567 @@
+// Audio input for the audio-reactive effects. PCM samples are read from an
+// ALSA capture device, a file or a FIFO, and analyzed in short overlapping
+// blocks into the loudness, a beat envelope and the levels of frequency
+// bands, which the renderer picks up with every frame.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use rustfft::num_complex::Complex;
+use rustfft::Fft;
+use rustfft::FftPlanner;
+
+use crate::args::AudioArgs;
+
+/// Number of samples per FFT, about 23ms at 44.1kHz.
+const FFT_SIZE: usize = 1024;
+
+/// Number of new samples between two analyses.
+const HOP_SIZE: usize = FFT_SIZE / 2;
+
+/// Number of frequency bands shown by the spectrum effect.
+pub const NUM_BANDS: usize = 16;
+
+const LOWEST_BAND: f32 = 40.0;
+const HIGHEST_BAND: f32 = 16000.0;
+
+/// Bass energy above this multiple of its recent average counts as a beat.
+const BEAT_THRESHOLD: f32 = 1.5;
+const BEAT_MAX_FREQUENCY: f32 = 150.0;
+const MIN_BEAT_INTERVAL: f32 = 0.25;
+
+/// Lower rates leave too few FFT bins for the bands.
+const MIN_RATE: u32 = 8000;
+
+/// Format chunks are 16 to 40 bytes long, anything much larger is not a WAV file.
+const MAX_FMT_SIZE: u64 = 64;
+
+/// Where the samples come from, given as `alsa:<device>`, `file:<path>` or `fifo:<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioSource {
+    /// An ALSA capture device like `default` or `hw:1,0`.
+    Alsa(String),
+    /// Played once in real time, then the input is silent.
+    File(PathBuf),
+    /// Reopened whenever the writer closes it, so that one file after
+    /// another can be written to it, e.g. with `cat song.wav > fifo`.
+    Fifo(PathBuf),
+}
+
+impl FromStr for AudioSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<AudioSource> {
+        return match s.split_once(':') {
+            Some(("alsa", device)) if !device.is_empty() => Ok(AudioSource::Alsa(device.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(AudioSource::File(PathBuf::from(path))),
+            Some(("fifo", path)) if !path.is_empty() => Ok(AudioSource::Fifo(PathBuf::from(path))),
+            _ => Err(anyhow!("invalid audio input '{}', expected alsa:DEVICE, file:PATH or fifo:PATH", s)),
+        };
+    }
+}
+
+/// Sample rate and channel count of 16 bit signed little-endian samples.
+/// Used for ALSA and for raw input, WAV files bring their own.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PcmFormat {
+    pub rate: u32,
+    pub channels: u16,
+}
+
+impl PcmFormat {
+    /// Rejects formats that the analysis can't handle.
+    fn check(self) -> std::io::Result<PcmFormat> {
+        if self.rate < MIN_RATE || self.channels == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported sample format with {}Hz and {} channels, need at least {}Hz and 1 channel",
+                        self.rate, self.channels, MIN_RATE),
+            ));
+        }
+        return Ok(self);
+    }
+}
+
+/// What the effects know about the music.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioFeatures {
+    /// The loudness in [0.0, 1.0], relative to the recent peaks.
+    pub volume: f32,
+    /// Jumps to 1.0 on a beat and decays towards 0.0 until the next one.
+    pub beat: f32,
+    /// Levels in [0.0, 1.0] of `NUM_BANDS` logarithmically spaced
+    /// frequency bands, lowest first. Empty without audio input.
+    pub bands: Vec<f32>,
+}
+
+/// Gives the renderer access to the most recent features. Cheap to
+/// clone, all clones observe the same analysis.
+#[derive(Clone, Debug, Default)]
+pub struct AudioInput {
+    features: Arc<Mutex<AudioFeatures>>,
+}
+
+impl AudioInput {
+    fn publish(&self, features: AudioFeatures) {
+        *self.features.lock().unwrap_or_else(|e| e.into_inner()) = features;
+    }
+
+    pub fn latest(&self) -> AudioFeatures {
+        return self.features.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    }
+}
+
+/// Turns a stream of mono samples into `AudioFeatures`. Levels are measured
+/// relative to slowly decaying peaks, so that the effects use their full
+/// range for quiet and loud music alike.
+pub struct Analyzer {
+    rate: f32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    // The last `FFT_SIZE` samples.
+    samples: Vec<f32>,
+    pending: Vec<f32>,
+    // Ranges of FFT bins.
+    band_bins: Vec<(usize, usize)>,
+    beat_bins: usize,
+    volume_reference: f32,
+    band_reference: f32,
+    bass_average: f32,
+    since_beat: f32,
+    features: AudioFeatures,
+}
+
+impl Analyzer {
+    pub fn new(rate: u32) -> Analyzer {
+        let rate = rate as f32;
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let bin = |frequency: f32| (frequency * FFT_SIZE as f32 / rate).round().max(1.0) as usize;
+        let highest = HIGHEST_BAND.min(rate / 2.0);
+        let mut band_bins = Vec::new();
+        for i in 0..NUM_BANDS {
+            let low = LOWEST_BAND * (highest / LOWEST_BAND).powf(i as f32 / NUM_BANDS as f32);
+            let high = LOWEST_BAND * (highest / LOWEST_BAND).powf((i + 1) as f32 / NUM_BANDS as f32);
+            let start = bin(low).min(FFT_SIZE / 2 - 1);
+            // Low bands are narrower than a bin, they get at least one.
+            band_bins.push((start, bin(high).clamp(start + 1, FFT_SIZE / 2)));
+        }
+        return Analyzer {
+            rate: rate,
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            window: window,
+            samples: vec![0.0; FFT_SIZE],
+            pending: Vec::with_capacity(HOP_SIZE),
+            band_bins: band_bins,
+            beat_bins: bin(BEAT_MAX_FREQUENCY).clamp(2, FFT_SIZE / 2),
+            volume_reference: -50.0,
+            band_reference: -60.0,
+            bass_average: 0.0,
+            since_beat: MIN_BEAT_INTERVAL,
+            features: AudioFeatures { volume: 0.0, beat: 0.0, bands: vec![0.0; NUM_BANDS] },
+        };
+    }
+
+    /// Adds mono samples in [-1.0, 1.0]. Returns whether the features changed.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        let mut changed = false;
+        for sample in samples {
+            self.pending.push(*sample);
+            if self.pending.len() == HOP_SIZE {
+                self.samples.drain(..HOP_SIZE);
+                self.samples.append(&mut self.pending);
+                self.analyze();
+                changed = true;
+            }
+        }
+        return changed;
+    }
+
+    pub fn features(&self) -> &AudioFeatures {
+        return &self.features;
+    }
+
+    fn analyze(&mut self) {
+        let dt = HOP_SIZE as f32 / self.rate;
+        let db = |power: f32| 10.0 * power.max(1e-12).log10();
+
+        // Loudness of the new samples. The reference drops by 3dB per second
+        // after a peak, and levels 30dB below it are silent.
+        let new = &self.samples[FFT_SIZE - HOP_SIZE..];
+        let level = db(new.iter().map(|x| x * x).sum::<f32>() / HOP_SIZE as f32);
+        self.volume_reference = level.max(self.volume_reference - 3.0 * dt).max(-50.0);
+        let volume = ((level - self.volume_reference + 30.0) / 30.0).clamp(0.0, 1.0);
+        // Rise at once, but fall smoothly so that the effects don't flicker.
+        self.features.volume = volume.max(self.features.volume - 2.0 * dt);
+
+        let mut spectrum: Vec<Complex<f32>> = self.samples
+            .iter()
+            .zip(&self.window)
+            .map(|(x, w)| Complex::new(x * w, 0.0))
+            .collect();
+        self.fft.process(&mut spectrum);
+        // A full-scale sine has a power of about 1.0 in its bin.
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        let power: Vec<f32> = spectrum[..FFT_SIZE / 2].iter().map(|x| (x * scale).norm_sqr()).collect();
+
+        // A beat is a sudden rise of the bass above its average of about the last second.
+        let bass: f32 = power[1..self.beat_bins].iter().sum();
+        self.since_beat += dt;
+        if bass > BEAT_THRESHOLD * self.bass_average && bass > 1e-6 && self.since_beat >= MIN_BEAT_INTERVAL {
+            self.features.beat = 1.0;
+            self.since_beat = 0.0;
+        } else {
+            self.features.beat *= (-dt / 0.15).exp();
+        }
+        self.bass_average += (bass - self.bass_average) * dt;
+
+        // Band levels over a range of 40dB below the loudest band of the recent past.
+        let levels: Vec<f32> = self.band_bins
+            .iter()
+            .map(|(start, end)| db(power[*start..*end].iter().sum::<f32>() / (end - start) as f32))
+            .collect();
+        let loudest = levels.iter().copied().fold(f32::MIN, f32::max);
+        self.band_reference = loudest.max(self.band_reference - 6.0 * dt).max(-60.0);
+        for (band, level) in self.features.bands.iter_mut().zip(levels) {
+            let target = ((level - self.band_reference + 40.0) / 40.0).clamp(0.0, 1.0);
+            *band = target.max(*band - 2.0 * dt);
+        }
+    }
+}
+
+/// Reads the header of a WAV file if the stream starts with one, and returns the
+/// format of the samples that follow. Otherwise, the stream is taken as raw
+/// samples in `format`, and the bytes that were read are returned with it.
+/// Either way, the format is checked before it's returned.
+fn read_header(stream: &mut impl Read, format: PcmFormat) -> std::io::Result<(PcmFormat, Vec<u8>)> {
+    let mut riff = [0u8; 12];
+    stream.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Ok((format.check()?, riff.to_vec()));
+    }
+    let invalid = |message: &str| std::io::Error::new(ErrorKind::InvalidData, message.to_string());
+    let mut wav_format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        stream.read_exact(&mut chunk)?;
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+        match &chunk[0..4] {
+            b"fmt " => {
+                if size < 16 {
+                    return Err(invalid("truncated WAV format chunk"));
+                }
+                if size > MAX_FMT_SIZE {
+                    return Err(invalid("oversized WAV format chunk"));
+                }
+                let mut fmt = vec![0u8; size as usize];
+                stream.read_exact(&mut fmt)?;
+                // 1 is PCM, 0xfffe is WAVE_FORMAT_EXTENSIBLE, which we only see for PCM in practice.
+                let tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                if !(tag == 1 || tag == 0xfffe) || bits != 16 {
+                    return Err(invalid("only 16 bit PCM WAV files are supported"));
+                }
+                wav_format = Some(PcmFormat {
+                    rate: u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]),
+                    channels: u16::from_le_bytes([fmt[2], fmt[3]]),
+                }.check()?);
+                if size % 2 == 1 {
+                    stream.read_exact(&mut [0u8; 1])?;
+                }
+            },
+            // The size of the data chunk is ignored, since tools that write
+            // to pipes can't know it in advance.
+            b"data" => return wav_format.map(|format| (format, Vec::new())).ok_or_else(|| invalid("WAV data before format")),
+            _ => {
+                std::io::copy(&mut stream.by_ref().take(size + size % 2), &mut std::io::sink())?;
+            },
+        }
+    }
+}
+
+/// Analyzes the samples of a file or FIFO until it ends, at the pace at which
+/// they would be played.
+fn analyze_stream(mut stream: impl Read, format: PcmFormat, input: &AudioInput) -> std::io::Result<()> {
+    let (format, prefix) = match read_header(&mut stream, format) {
+        Ok(header) => header,
+        // Ended before it started.
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    log::info!(rate = format.rate, channels = format.channels; "analyzing audio");
+    let mut stream = std::io::Cursor::new(prefix).chain(stream);
+    let mut analyzer = Analyzer::new(format.rate);
+    let frame_size = 2 * format.channels as usize;
+    let mut buffer = vec![0u8; HOP_SIZE * frame_size];
+    let mut filled = 0;
+    let mut frames = 0u64;
+    let started = Instant::now();
+    loop {
+        let n = stream.read(&mut buffer[filled..])?;
+        if n == 0 {
+            return Ok(());
+        }
+        filled += n;
+        let complete = filled - filled % frame_size;
+        analyzer.push(&mono(&buffer[..complete], format.channels));
+        buffer.copy_within(complete..filled, 0);
+        filled -= complete;
+        frames += (complete / frame_size) as u64;
+
+        input.publish(analyzer.features().clone());
+        let due = started + Duration::from_secs_f64(frames as f64 / format.rate as f64);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Mixes interleaved 16 bit little-endian samples down to mono.
+fn mono(bytes: &[u8], channels: u16) -> Vec<f32> {
+    return bytes
+        .chunks_exact(2 * channels as usize)
+        .map(|frame| {
+            let sum: f32 = frame.chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]]) as f32).sum();
+            sum / (channels as f32 * 32768.0)
+        })
+        .collect();
+}
+
+#[cfg(feature = "alsa")]
+fn capture_alsa(device: &str, format: PcmFormat, input: &AudioInput) -> anyhow::Result<()> {
+    use alsa::pcm::Access;
+    use alsa::pcm::Format;
+    use alsa::pcm::HwParams;
+    use alsa::Direction;
+    use alsa::ValueOr;
+    use alsa::PCM;
+
+    let pcm = PCM::new(device, Direction::Capture, false).map_err(|e| anyhow!("couldn't open ALSA device {}: {}", device, e))?;
+    {
+        let params = HwParams::any(&pcm)?;
+        params.set_channels(format.channels as u32)?;
+        params.set_rate(format.rate, ValueOr::Nearest)?;
+        params.set_format(Format::s16())?;
+        params.set_access(Access::RWInterleaved)?;
+        pcm.hw_params(&params)?;
+    }
+    // The device may not support the requested rate.
+    let rate = PcmFormat { rate: pcm.hw_params_current()?.get_rate()?, channels: format.channels }.check()?.rate;
+    log::info!(rate = rate, channels = format.channels; "capturing audio from {}", device);
+    let io = pcm.io_i16()?;
+    let mut analyzer = Analyzer::new(rate);
+    let mut buffer = vec![0i16; HOP_SIZE * format.channels as usize];
+    loop {
+        let frames = match io.readi(&mut buffer) {
+            Ok(frames) => frames,
+            // Overruns happen when we're too slow, just carry on.
+            Err(e) => {
+                pcm.try_recover(e, true)?;
+                continue;
+            },
+        };
+        let samples: Vec<f32> = buffer[..frames * format.channels as usize]
+            .chunks_exact(format.channels as usize)
+            .map(|frame| frame.iter().map(|x| *x as f32).sum::<f32>() / (format.channels as f32 * 32768.0))
+            .collect();
+        if analyzer.push(&samples) {
+            input.publish(analyzer.features().clone());
+        }
+    }
+}
+
+#[cfg(not(feature = "alsa"))]
+fn capture_alsa(_device: &str, _format: PcmFormat, _input: &AudioInput) -> anyhow::Result<()> {
+    bail!("built without the 'alsa' feature");
+}
+
+/// Starts a thread that analyzes the audio from `source` and publishes the
+/// features to `input`. Raw samples and ALSA devices use `format`.
+pub fn spawn_source(source: AudioSource, format: PcmFormat, input: AudioInput) -> anyhow::Result<()> {
+    format.check()?;
+    match &source {
+        AudioSource::Alsa(_) => {
+            if !cfg!(feature = "alsa") {
+                bail!("built without the 'alsa' feature");
+            }
+        },
+        AudioSource::File(path) => {
+            File::open(path).map_err(|e| anyhow!("couldn't open {}: {}", path.display(), e))?;
+        },
+        AudioSource::Fifo(path) => {
+            let metadata = std::fs::metadata(path).map_err(|e| anyhow!("couldn't open {}: {}", path.display(), e))?;
+            if !metadata.file_type().is_fifo() {
+                bail!("{} is not a FIFO, create it with mkfifo", path.display());
+            }
+        },
+    }
+    std::thread::spawn(move || {
+        let result = match &source {
+            AudioSource::Alsa(device) => capture_alsa(device, format, &input),
+            AudioSource::File(path) => File::open(path)
+                .and_then(|file| analyze_stream(file, format, &input))
+                .map_err(|e| anyhow!("{}: {}", path.display(), e)),
+            AudioSource::Fifo(path) => loop {
+                // Blocks until a writer opens the FIFO.
+                let result = File::open(path).and_then(|fifo| analyze_stream(fifo, format, &input));
+                input.publish(AudioFeatures::default());
+                match result {
+                    Ok(()) => log::debug!("audio FIFO closed by the writer"),
+                    Err(e) => {
+                        log::warn!("couldn't read audio from {}: {}", path.display(), e);
+                        // Don't spin if the FIFO can't be opened at all.
+                        std::thread::sleep(Duration::from_secs(1));
+                    },
+                }
+            },
+        };
+        // Don't leave the effects dancing to the last block.
+        input.publish(AudioFeatures::default());
+        match result {
+            Ok(()) => log::info!("audio input ended"),
+            Err(e) => log::error!("audio input stopped: {}", e),
+        }
+    });
+    return Ok(());
+}
+
+/// Starts the analysis of the audio input given on the command line, if any.
+pub fn spawn(args: &AudioArgs, input: AudioInput) -> anyhow::Result<()> {
+    let source = match &args.audio_input {
+        Some(source) => source.parse::<AudioSource>()?,
+        None => return Ok(()),
+    };
+    let format = PcmFormat { rate: args.audio_rate, channels: args.audio_channels };
+    return spawn_source(source, format, input);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: PcmFormat = PcmFormat { rate: 44100, channels: 2 };
+
+    fn wav_header(tag: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVE");
+        // Chunks that aren't needed are skipped, including the padding byte.
+        header.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        header.extend_from_slice(b"fmt \x10\0\0\0");
+        header.extend_from_slice(&tag.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&rate.to_le_bytes());
+        header.extend_from_slice(&(rate * channels as u32 * 2).to_le_bytes());
+        header.extend_from_slice(&(channels * 2).to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        header.extend_from_slice(b"data\xff\xff\xff\xff");
+        return header;
+    }
+
+    fn sine(frequency: f32, rate: u32, seconds: f32) -> Vec<f32> {
+        return (0..(rate as f32 * seconds) as usize)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * frequency * i as f32 / rate as f32).sin())
+            .collect();
+    }
+
+    #[test]
+    fn wav_header_gives_the_format() {
+        let mut stream = wav_header(1, 1, 22050, 16);
+        stream.extend_from_slice(&[1, 2, 3, 4]);
+        let mut stream = stream.as_slice();
+        let (format, prefix) = read_header(&mut stream, RAW).unwrap();
+        assert_eq!(format, PcmFormat { rate: 22050, channels: 1 });
+        assert!(prefix.is_empty());
+        assert_eq!(stream, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn raw_samples_keep_the_given_format() {
+        let samples: Vec<u8> = (0..16).collect();
+        let (format, prefix) = read_header(&mut samples.as_slice(), RAW).unwrap();
+        assert_eq!(format, RAW);
+        assert_eq!(prefix, &samples[..12]);
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let invalid = |stream: Vec<u8>, format: PcmFormat| {
+            read_header(&mut stream.as_slice(), format).unwrap_err().kind() == ErrorKind::InvalidData
+        };
+        assert!(invalid(wav_header(3, 2, 44100, 32), RAW));
+        assert!(invalid(wav_header(1, 2, 44100, 8), RAW));
+        assert!(invalid(wav_header(1, 0, 44100, 16), RAW));
+        assert!(invalid(wav_header(1, 2, 100, 16), RAW));
+        assert!(invalid(vec![0; 16], PcmFormat { rate: 44100, channels: 0 }));
+        assert!(invalid(vec![0; 16], PcmFormat { rate: 200, channels: 2 }));
+        let mut data_first = b"RIFF\0\0\0\0WAVE".to_vec();
+        data_first.extend_from_slice(b"data\0\0\0\0");
+        assert!(invalid(data_first, RAW));
+        for size in [b"\x08\0\0\0", b"\xff\xff\xff\xff"] {
+            let mut fmt = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+            fmt.extend_from_slice(size);
+            assert!(invalid(fmt, RAW));
+        }
+    }
+
+    #[test]
+    fn mono_mixes_channels() {
+        let bytes: Vec<u8> = [16384i16, 16384, 16384, -16384, -32768, -32768, 100]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        // The incomplete last frame is dropped.
+        assert_eq!(mono(&bytes, 2), vec![0.5, 0.0, -1.0]);
+        assert_eq!(mono(&bytes[..4], 1), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn sine_lights_its_band() {
+        let mut analyzer = Analyzer::new(44100);
+        assert!(analyzer.push(&sine(1000.0, 44100, 1.0)));
+        let bands = &analyzer.features().bands;
+        // 1kHz is in the 9th of the bands from 40Hz to 16kHz.
+        let loudest = (0..NUM_BANDS).max_by(|a, b| bands[*a].total_cmp(&bands[*b])).unwrap();
+        assert_eq!(loudest, 8);
+        assert_eq!(bands[8], 1.0);
+        assert_eq!(bands[0], 0.0);
+        assert_eq!(bands[NUM_BANDS - 1], 0.0);
+        assert!(analyzer.features().volume > 0.9);
+        // Only its start may count as a beat, which has decayed since.
+        assert!(analyzer.features().beat < 0.01);
+    }
+
+    #[test]
+    fn clicks_are_beats() {
+        let rate = 44100;
+        let mut clicks = vec![0.0; 4 * rate];
+        for beat in 1..8 {
+            clicks[beat * rate / 2] = 1.0;
+        }
+        let mut analyzer = Analyzer::new(rate as u32);
+        let mut beats = 0;
+        for block in clicks.chunks(HOP_SIZE) {
+            analyzer.push(block);
+            if analyzer.features().beat == 1.0 {
+                beats += 1;
+            }
+        }
+        assert_eq!(beats, 7);
+    }
+
+    #[test]
+    fn low_rates_dont_panic() {
+        let mut analyzer = Analyzer::new(200);
+        assert!(analyzer.push(&sine(50.0, 200, 20.0)));
+    }
+}
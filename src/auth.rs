@@ -0,0 +1,86 @@
+// Access control for the REST API. The server extracts the credentials
+// from a request, and this module decides whether they allow the request.
+
+use crate::config::AuthConfig;
+use crate::config::Scope;
+
+/// Credentials sent with a request.
+#[derive(Copy, Clone, Debug)]
+pub enum Credentials<'a> {
+    Bearer(&'a str),
+    Basic { user: &'a str, password: &'a str },
+}
+
+/// Why a request was rejected.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Denied {
+    /// The request has no or unknown credentials.
+    Unauthenticated,
+    /// The credentials are valid, but don't grant the required scope.
+    Forbidden,
+}
+
+/// Extracts the token from the value of an `Authorization` header.
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    return Some(token.trim());
+}
+
+/// The scope that a request needs.
+pub fn required_scope(method: &str) -> Scope {
+    return match method {
+        "GET" | "HEAD" => Scope::Read,
+        _ => Scope::Control,
+    };
+}
+
+/// Compares in a time that only depends on the lengths, so that
+/// response times don't reveal how much of a secret was guessed.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y));
+    return difference == 0;
+}
+
+/// The scope granted by the given credentials, or `None` if they are unknown.
+pub fn scope(config: &AuthConfig, credentials: Credentials) -> Option<Scope> {
+    return match credentials {
+        Credentials::Bearer(token) => config.tokens
+            .iter()
+            .filter(|entry| constant_time_eq(&entry.token, token))
+            .map(|entry| entry.scope)
+            .max(),
+        Credentials::Basic { user, password } => config.users
+            .iter()
+            .filter(|entry| entry.name == user && constant_time_eq(&entry.password, password))
+            .map(|entry| entry.scope)
+            .max(),
+    };
+}
+
+pub fn is_enabled(config: &AuthConfig) -> bool {
+    return !config.tokens.is_empty() || !config.users.is_empty();
+}
+
+/// Decides whether a request for `path` with the given method and credentials is allowed.
+pub fn authorize(config: &AuthConfig, method: &str, path: &str, credentials: Option<Credentials>) -> Result<(), Denied> {
+    if !is_enabled(config) {
+        return Ok(());
+    }
+    let required = required_scope(method);
+    if config.anonymous_status && required == Scope::Read && (path == "/about" || path == "/status") {
+        return Ok(());
+    }
+    let granted = credentials
+        .and_then(|credentials| scope(config, credentials))
+        .ok_or(Denied::Unauthenticated)?;
+    if granted < required {
+        return Err(Denied::Forbidden);
+    }
+    return Ok(());
+}
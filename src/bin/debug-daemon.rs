@@ -1,44 +1,95 @@
+use std::io::Write;
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
 use std::thread;
 
 use clap::Parser;
 
 use firelight::daemon;
-use firelight::args::DaemonArgs;
+use firelight::daemon::DaemonStats;
+use firelight::args::DebugDaemonArgs;
+use firelight::layout::Layout;
+use firelight::logging;
+use firelight::preview;
 
-fn handle_client(mut stream: UnixStream) -> anyhow::Result<()> {
+fn handle_client(mut stream: UnixStream, args: Arc<DebugDaemonArgs>, stats: Arc<Mutex<DaemonStats>>) -> anyhow::Result<()> {
     let mut buffer: [u32; 256] = [0; 256];
+    let mut frame_number: u64 = 0;
     loop {
         let n = daemon::read_input(&mut stream, &mut buffer)?;
         if n == 0 {
             break;
         }
-        println!("got {} colors", n);
+        if daemon::is_stats_query(&buffer[0..n]) {
+            let stats = stats.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            daemon::write_stats(&stream, &stats)?;
+            continue;
+        }
+        // Drawing to the terminal takes the place of the hardware render time.
+        let started = Instant::now();
+        let colors = &buffer[0..n];
+        let layout = if args.strands.is_empty() {
+            Layout::new(vec![n])
+        } else {
+            Layout::new(args.strands.clone())
+        };
+        if args.quiet {
+            println!("got {} colors", n);
+        } else {
+            // Move the cursor to the top left and redraw the previous frame in place.
+            let mut stdout = std::io::stdout().lock();
+            write!(stdout, "\x1b[H\x1b[2J{}", preview::to_ansi(&layout, colors))?;
+            stdout.flush()?;
+        }
+        if let Some(dir) = &args.dump_ppm {
+            let path = PathBuf::from(dir).join(format!("frame-{:06}.ppm", frame_number));
+            std::fs::write(path, preview::to_ppm(&layout, colors, args.scale))?;
+        }
+        frame_number += 1;
+        stats.lock().unwrap_or_else(|e| e.into_inner()).record_render(started.elapsed());
     }
     return Ok(());
 }
 
-/// A debug version of lightingd that only prints the color pattern it would apply.
-/// TODO: Open a window and draw results.
+/// A debug version of lightingd that draws the color pattern it would apply
+/// to the terminal.
 fn main() -> anyhow::Result<()> {
-    let args = DaemonArgs::parse();
+    let args = Arc::new(DebugDaemonArgs::parse());
+    logging::init(&args.log, None)?;
+    if let Some(dir) = &args.dump_ppm {
+        std::fs::create_dir_all(dir)?;
+    }
     // It would be cleaner to delete this on shutdown using RAII,
     // but rust doesn't unwind after signals.
     if Path::new(&args.unix_socket).exists() {
         std::fs::remove_file(&args.unix_socket)?;
     }
+    let stats = Arc::new(Mutex::new(DaemonStats::default()));
     let listener = UnixListener::bind(&args.unix_socket)?;
-    println!("listening on {}", args.unix_socket);
+    log::info!("listening on {}", args.unix_socket);
+    let mut next_client: u64 = 1;
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                println!("new client");
-                thread::spawn(|| handle_client(stream));
+                let client = next_client;
+                next_client += 1;
+                log::info!(client = client; "new client");
+                let thread_args = args.clone();
+                let thread_stats = stats.clone();
+                thread::spawn(move || {
+                    match handle_client(stream, thread_args, thread_stats) {
+                        Ok(()) => log::info!(client = client; "client disconnected"),
+                        Err(e) => log::warn!(client = client; "client failed: {}", e),
+                    }
+                });
             }
             Err(err) => {
-                println!("couldn't accept client: {}", err);
+                log::warn!("couldn't accept client: {}", err);
                 continue;
             }
         }
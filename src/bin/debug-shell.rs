@@ -1,56 +1,382 @@
-use std::os::unix::net::UnixStream;
-use std::io::Write;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use clap::Parser;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::Context;
+use rustyline::Editor;
+use rustyline::Helper;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
 
-use firelight::args::ServerArgs;
+use firelight::args::ShellArgs;
+use firelight::audio;
+use firelight::logging;
+use firelight::Control;
+use firelight::Effect;
 
-macro_rules! skip_fail {
-    ($res:expr) => {
-        match $res {
-            Ok(val) => val,
-            Err(e) => {
-                println!("Invalid value: {}", e);
+const HELP: &str = "\
+Several commands can be given on one line, separated by whitespace.
+Assignments are collected and sent to the renderer together, either
+at the end of the line or before the next command that isn't one.
+
+  KEY=VALUE        set a value
+  KEY+=N, KEY-=N   increase or decrease a numeric value
+  get KEY          print a value
+  status           print all values
+  toggle           turn the lamp on or off
+  sleep SECONDS    wait before executing the next command
+  help             show this message
+  quit             leave the shell
+
+Valid keys are: on (true/false), effect (static/fire/audiofire/vu/spectrum),
+brightness (0-255), h (0-360), s (0-100), speed (0-10). Lines starting with
+'#' are ignored. The audio effects need --audio-input.";
+
+/// Keys that can be assigned, in the order in which `status` prints them.
+const KEYS: &[&str] = &["on", "effect", "brightness", "h", "s", "speed"];
+
+const COMMANDS: &[&str] = &["help", "status", "get", "toggle", "sleep", "quit"];
+
+enum Command {
+    Help,
+    Status,
+    Get(String),
+    Toggle,
+    Sleep(Duration),
+    Quit,
+    Set(Setting),
+    Adjust(String, f32),
+}
+
+/// A value assigned to a key, parsed and checked against its range.
+enum Setting {
+    On(bool),
+    Effect(Effect),
+    Brightness(u8),
+    Hue(f32),
+    Saturation(f32),
+    Speed(f32),
+}
+
+fn check_key(key: &str) -> anyhow::Result<String> {
+    if !KEYS.contains(&key) {
+        bail!("unknown key '{}'", key);
+    }
+    return Ok(key.to_string());
+}
+
+fn parse_number(value: &str, min: f32, max: f32) -> anyhow::Result<f32> {
+    let number = value.parse::<f32>().map_err(|e| anyhow!("'{}': {}", value, e))?;
+    if !(min..=max).contains(&number) {
+        bail!("'{}' is not in [{}, {}]", value, min, max);
+    }
+    return Ok(number);
+}
+
+fn parse_setting(key: &str, value: &str) -> anyhow::Result<Setting> {
+    return match key {
+        "on" => Ok(Setting::On(value.parse::<bool>()?)),
+        "effect" => Ok(Setting::Effect(Effect::from_string(value)?)),
+        "brightness" => {
+            let brightness = value.parse::<u8>().map_err(|_| anyhow!("'{}' is not an integer in [0, 255]", value))?;
+            Ok(Setting::Brightness(brightness))
+        },
+        "h" => Ok(Setting::Hue(parse_number(value, 0.0, 360.0)?)),
+        "s" => Ok(Setting::Saturation(parse_number(value, 0.0, 100.0)?)),
+        "speed" => Ok(Setting::Speed(parse_number(value, 0.0, 10.0)?)),
+        _ => unreachable!("keys are checked before"),
+    };
+}
+
+fn parse_delta(key: &str, value: &str) -> anyhow::Result<f32> {
+    if !matches!(key, "brightness" | "h" | "s" | "speed") {
+        bail!("'{}' is not numeric", key);
+    }
+    let delta = value.parse::<f32>().map_err(|e| anyhow!("invalid value '{}': {}", value, e))?;
+    if !delta.is_finite() {
+        bail!("invalid value '{}'", value);
+    }
+    return Ok(delta);
+}
+
+/// Parses and checks a complete line before anything is executed,
+/// so that a typo doesn't leave the lamp in a half-updated state.
+fn parse_line(line: &str) -> anyhow::Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    if line.trim_start().starts_with('#') {
+        return Ok(commands);
+    }
+    let mut tokens = line.split_whitespace();
+    while let Some(token) = tokens.next() {
+        let command = match token {
+            "help" => Command::Help,
+            "status" => Command::Status,
+            "toggle" => Command::Toggle,
+            "quit" | "exit" => Command::Quit,
+            "get" => {
+                let key = tokens.next().ok_or_else(|| anyhow!("'get' expects a key"))?;
+                Command::Get(check_key(key)?)
+            }
+            "sleep" => {
+                let text = tokens.next().ok_or_else(|| anyhow!("'sleep' expects a duration"))?;
+                let seconds = text.parse::<f32>().map_err(|e| anyhow!("invalid duration '{}': {}", text, e))?;
+                // Rejects negative, infinite and too large values.
+                let duration = Duration::try_from_secs_f32(seconds)
+                    .map_err(|e| anyhow!("invalid duration '{}': {}", text, e))?;
+                Command::Sleep(duration)
+            }
+            _ => {
+                if let Some((key, value)) = token.split_once("+=") {
+                    let key = check_key(key)?;
+                    let delta = parse_delta(&key, value)?;
+                    Command::Adjust(key, delta)
+                } else if let Some((key, value)) = token.split_once("-=") {
+                    let key = check_key(key)?;
+                    let delta = parse_delta(&key, value)?;
+                    Command::Adjust(key, -delta)
+                } else if let Some((key, value)) = token.split_once('=') {
+                    let key = check_key(key)?;
+                    let setting = parse_setting(&key, value).map_err(|e| anyhow!("invalid value for '{}': {}", key, e))?;
+                    Command::Set(setting)
+                } else {
+                    bail!("unknown command '{}', try 'help'", token);
+                }
+            }
+        };
+        commands.push(command);
+    }
+    return Ok(commands);
+}
+
+fn get(control: &Control, key: &str) -> String {
+    return match key {
+        "on" => control.on.to_string(),
+        "effect" => control.effect.to_string(),
+        "brightness" => control.brightness.to_string(),
+        "h" => control.color_hs.0.to_string(),
+        "s" => control.color_hs.1.to_string(),
+        "speed" => control.speed.to_string(),
+        _ => unreachable!("keys are checked while parsing"),
+    };
+}
+
+fn set(control: &mut Control, setting: Setting) {
+    match setting {
+        Setting::On(on) => control.on = on,
+        Setting::Effect(effect) => control.effect = effect,
+        Setting::Brightness(brightness) => control.brightness = brightness,
+        Setting::Hue(hue) => control.color_hs.0 = hue,
+        Setting::Saturation(saturation) => control.color_hs.1 = saturation,
+        Setting::Speed(speed) => control.speed = speed,
+    }
+}
+
+fn adjust(control: &mut Control, key: &str, delta: f32) {
+    match key {
+        "brightness" => control.brightness = (control.brightness as f32 + delta).clamp(0.0, 255.0) as u8,
+        "h" => control.color_hs.0 = (control.color_hs.0 + delta).rem_euclid(360.0),
+        "s" => control.color_hs.1 = (control.color_hs.1 + delta).clamp(0.0, 100.0),
+        "speed" => control.speed = (control.speed + delta).clamp(0.0, 10.0),
+        _ => unreachable!("numeric keys are checked while parsing"),
+    }
+}
+
+// The handle is shared with the signal handler thread.
+type Device = Arc<Mutex<firelight::Handle>>;
+
+fn lock(device: &Device) -> MutexGuard<'_, firelight::Handle> {
+    return device.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+/// Only fails if the change wasn't accepted. If just the daemon is unavailable,
+/// the change is shown once the renderer has reconnected, so scripts go on.
+fn delivered(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    if let Err(e) = result {
+        if !e.is::<firelight::DaemonUnavailable>() {
+            return Err(e);
+        }
+        log::warn!("{}", e);
+    }
+    return Ok(());
+}
+
+/// Executes one line of input. Returns `false` if the shell should exit.
+fn execute(device: &Device, commands: Vec<Command>) -> anyhow::Result<bool> {
+    let mut pending: Option<Control> = None;
+    for command in commands {
+        match command {
+            Command::Set(setting) => {
+                set(pending.get_or_insert_with(|| lock(device).state()), setting);
+                continue;
+            }
+            Command::Adjust(key, delta) => {
+                adjust(pending.get_or_insert_with(|| lock(device).state()), &key, delta);
                 continue;
             }
+            _ => (),
         }
-    };
+        if let Some(control) = pending.take() {
+            delivered(lock(device).control(control))?;
+        }
+        match command {
+            Command::Help => println!("{}", HELP),
+            Command::Status => {
+                let state = lock(device).state();
+                for key in KEYS {
+                    println!("{}={}", key, get(&state, key));
+                }
+            }
+            Command::Get(key) => println!("{}", get(&lock(device).state(), &key)),
+            Command::Toggle => delivered(lock(device).toggle())?,
+            Command::Sleep(duration) => std::thread::sleep(duration),
+            Command::Quit => return Ok(false),
+            Command::Set(..) | Command::Adjust(..) => unreachable!(),
+        }
+    }
+    if let Some(control) = pending.take() {
+        delivered(lock(device).control(control))?;
+    }
+    return Ok(true);
 }
 
-/// A shell for interactive debugging.
-fn main() -> anyhow::Result<()> {
-    let args = ServerArgs::parse();
-    println!("{:?}", args.strands);
-    let socket = UnixStream::connect(args.daemon_socket)?;
-    let mut device = firelight::Handle::new(socket, args.strands);
-    let mut input = String::new();
-    let mut control = firelight::Control::default();
+/// Runs a script non-interactively, stopping at the first error.
+fn run_script(device: &Device, input: Box<dyn BufRead>) -> anyhow::Result<()> {
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let keep_going = parse_line(&line)
+            .and_then(|commands| execute(device, commands))
+            .map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        if !keep_going {
+            break;
+        }
+    }
+    return Ok(());
+}
+
+/// Completes command names and keys, and the values of `effect` and `on`.
+struct ShellHelper {}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..pos];
+        let previous = line[..start].split_whitespace().last();
+
+        let candidates: Vec<String> = if let Some(value) = word.strip_prefix("effect=") {
+            Effect::all()
+                .iter()
+                .map(|effect| effect.to_string())
+                .filter(|name| name.starts_with(value))
+                .map(|name| format!("effect={}", name))
+                .collect()
+        } else if let Some(value) = word.strip_prefix("on=") {
+            ["true", "false"]
+                .iter()
+                .filter(|name| name.starts_with(value))
+                .map(|name| format!("on={}", name))
+                .collect()
+        } else if previous == Some("get") {
+            KEYS.iter().filter(|key| key.starts_with(word)).map(|key| key.to_string()).collect()
+        } else {
+            COMMANDS
+                .iter()
+                .map(|command| command.to_string())
+                .chain(KEYS.iter().map(|key| format!("{}=", key)))
+                .filter(|candidate| candidate.starts_with(word))
+                .collect()
+        };
+        return Ok((start, candidates));
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn history_path() -> Option<std::path::PathBuf> {
+    return std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".firelight_history"));
+}
+
+fn run_interactive(device: &Device) -> anyhow::Result<()> {
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper {}));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history yet when the shell is started for the first time.
+        let _ = editor.load_history(path);
+    }
     loop {
-        print!("firelight> ");
-        std::io::stdout().flush().unwrap();
-        input.clear();
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect("error: unable to read user input");
-        if input.ends_with('\n') {
-            input.pop();
-        }
-        let split = input.split("=");
-        let vec : Vec<&str> = split.collect();
-        if vec.len() != 2 {
-            println!("expected input in the form of key=value");
-            continue;
-        }
-        let (key, value) = (vec[0], vec[1]);
-        match key {
-            "help" => println!("Valid commands are: brightness=NUM, h=FLOAT, s=FLOAT, effect=STRING, on=BOOL"),
-            "brightness" => control.brightness = skip_fail!(value.parse::<u8>()),
-            "h" => control.color_hs.0 = skip_fail!(value.parse::<f32>()),
-            "s" => control.color_hs.1 = skip_fail!(value.parse::<f32>()),
-            "effect" => control.effect = skip_fail!(firelight::Effect::from_string(value)),
-            "on" => control.on = skip_fail!(value.parse::<bool>()),
-            _ => { println!("unknown key {}", key); continue; },
-        }
-        device.control(control);
+        let line = match editor.readline("firelight> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        editor.add_history_entry(line.as_str());
+        match parse_line(&line).and_then(|commands| execute(device, commands)) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
     }
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            log::warn!("couldn't save history: {}", e);
+        }
+    }
+    return Ok(());
+}
+
+/// Fades out the lamp before exiting on SIGTERM or SIGINT.
+fn shutdown_on_signals(device: Device) -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            log::info!("received signal {}, shutting down", signal);
+            lock(&device).shutdown();
+            std::process::exit(0);
+        }
+    });
+    return Ok(());
+}
+
+/// A shell for interactive debugging.
+fn main() -> anyhow::Result<()> {
+    let args = ShellArgs::parse();
+    logging::init(&args.log, None)?;
+    let handle = firelight::Handle::new(Path::new(&args.daemon_socket), args.strands)?;
+    audio::spawn(&args.audio, handle.audio_input())?;
+    let device = Arc::new(Mutex::new(handle));
+    shutdown_on_signals(device.clone())?;
+    let result = match args.script.as_deref() {
+        None => run_interactive(&device),
+        Some("-") => run_script(&device, Box::new(std::io::stdin().lock())),
+        Some(path) => {
+            let file = std::fs::File::open(path).map_err(|e| anyhow!("couldn't open {}: {}", path, e))?;
+            run_script(&device, Box::new(std::io::BufReader::new(file)))
+        }
+    };
+    // The signal handler keeps another reference, so the handle won't be dropped here.
+    lock(&device).shutdown();
+    return result;
 }
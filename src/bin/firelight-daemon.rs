@@ -1,26 +1,83 @@
+use std::ffi::CString;
 use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
-use std::ops::DerefMut;
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::MutexGuard;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
+use anyhow::bail;
 use clap::Parser;
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
 use std::sync::Mutex;
 
 use firelight::daemon;
 use firelight::args::DaemonArgs;
+use firelight::config::DaemonConfig;
+use firelight::config::SocketConfig;
+use firelight::config::StripConfig;
+use firelight::config::WatchdogAction;
+use firelight::config::WatchdogConfig;
+use firelight::ledstrip::ColorTransform;
 use firelight::ledstrip::DeviceController;
+use firelight::logging;
+use firelight::systemd;
+use firelight::renderer::LedColor;
+
+// How long the watchdog takes to fade to black, and how often it checks.
+const WATCHDOG_FADE: Duration = Duration::from_secs(2);
+const WATCHDOG_TICK: Duration = Duration::from_millis(1000 / 30);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Watchdog {
+    /// Frames are arriving in time.
+    Watching,
+    /// The timeout passed and the watchdog is changing the strip.
+    Fired,
+    /// The watchdog is done, until the next frame arrives.
+    Done,
+}
 
 struct DaemonState {
-    hw: DeviceController,
+    // Only `None` if the strip couldn't be initialized again
+    // after a reload, in which case frames are dropped.
+    hw: Option<DeviceController>,
+    strip: StripConfig,
+    transform: ColorTransform,
+
+    watchdog_config: WatchdogConfig,
+    watchdog: Watchdog,
+    last_frame: Instant,
+    last_colors: Vec<u32>,
+
+    socket: SocketConfig,
+
+    stats: daemon::DaemonStats,
+}
+
+fn lock(state: &Mutex<DaemonState>) -> MutexGuard<'_, DaemonState> {
+    // A client thread that panicked while applying a frame
+    // doesn't leave the hardware in an inconsistent state.
+    return state.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+fn open_strip(strip: &StripConfig, transform: &ColorTransform) -> anyhow::Result<DeviceController> {
+    let mut hw = DeviceController::new(strip.dma, strip.channel, strip.pin, strip.leds_count)?;
+    hw.set_transform(transform.clone());
+    return Ok(hw);
 }
 
 fn handle_client(
     mut stream: UnixStream,
     state_mutex: Arc<Mutex<DaemonState>>,
+    client: u64,
 ) -> anyhow::Result<()> {
     let mut buffer: [u32; 256] = [0; 256];
     loop {
@@ -28,46 +85,277 @@ fn handle_client(
         if n == 0 {
             break;
         }
-        let maybe_state = state_mutex.lock();
-        match maybe_state {
-            Ok(mut state) => state.deref_mut().hw.apply(&buffer[0..n]),
-            Err(e) => {
-                println!("shared state is poisoned : {}", e);
-                break;
+        if daemon::is_stats_query(&buffer[0..n]) {
+            let stats = lock(&state_mutex).stats.clone();
+            daemon::write_stats(&stream, &stats)?;
+            continue;
+        }
+        let mut state = lock(&state_mutex);
+        let started = Instant::now();
+        if let Some(hw) = &mut state.hw {
+            hw.apply(&buffer[0..n]);
+            state.stats.record_render(started.elapsed());
+        } else {
+            state.stats.frames_dropped += 1;
+        }
+        if state.watchdog != Watchdog::Watching {
+            log::info!(client = client; "watchdog: receiving frames again");
+            state.watchdog = Watchdog::Watching;
+        }
+        state.last_frame = Instant::now();
+        state.last_colors.clear();
+        state.last_colors.extend_from_slice(&buffer[0..n]);
+    }
+    return Ok(());
+}
+
+/// Takes over the strip if no frame arrived for the configured time, so that
+/// a hanging or crashed renderer doesn't leave its last frame on forever.
+fn watchdog_thread(state_mutex: Arc<Mutex<DaemonState>>) {
+    loop {
+        thread::sleep(WATCHDOG_TICK);
+        let mut state = lock(&state_mutex);
+        let timeout = match state.watchdog_config.timeout {
+            Some(timeout) => Duration::from_secs_f32(timeout),
+            None => continue,
+        };
+        let idle = state.last_frame.elapsed();
+        if idle < timeout || state.watchdog == Watchdog::Done {
+            continue;
+        }
+        let action = state.watchdog_config.action;
+        if state.watchdog == Watchdog::Watching {
+            log::warn!(
+                "watchdog: no frame received for {:.1} seconds, {}",
+                idle.as_secs_f32(),
+                match action {
+                    WatchdogAction::Fade => "fading to black",
+                    WatchdogAction::Fallback => "showing the fallback color",
+                }
+            );
+            state.watchdog = Watchdog::Fired;
+        }
+        let colors: Vec<u32> = match action {
+            WatchdogAction::Fade => {
+                let progress = ((idle - timeout).as_secs_f32() / WATCHDOG_FADE.as_secs_f32()).min(1.0);
+                if progress >= 1.0 {
+                    state.watchdog = Watchdog::Done;
+                }
+                state.last_colors
+                    .iter()
+                    .map(|color| LedColor::from_u32_rgb(*color).scaled(1.0 - progress).to_u32_rgb())
+                    .collect()
             }
+            WatchdogAction::Fallback => {
+                state.watchdog = Watchdog::Done;
+                vec![state.watchdog_config.fallback_color; state.strip.leds_count]
+            }
+        };
+        if let Some(hw) = &mut state.hw {
+            hw.apply(&colors);
         }
-        
+    }
+}
+
+/// Looks up a group by name, or parses a numeric group id.
+fn group_id(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    // Only called from the main thread and the signal thread, which
+    // holds the state lock, so the static result can't be overwritten.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("unknown group '{}'", group);
+    }
+    return Ok(unsafe { (*entry).gr_gid });
+}
+
+fn set_socket_permissions(path: &str, socket: &SocketConfig) -> anyhow::Result<()> {
+    std::fs::set_permissions(path, Permissions::from_mode(socket.mode))?;
+    if let Some(group) = &socket.group {
+        std::os::unix::fs::chown(path, None, Some(group_id(group)?))?;
     }
     return Ok(());
 }
 
+/// Reads the config file again and re-initializes the strip if its settings changed.
+fn reload(state: &mut DaemonState, args: &DaemonArgs, activated: bool) -> anyhow::Result<()> {
+    let config = DaemonConfig::from_args(args)?;
+    let strip = config.strip(args)?;
+    let transform = ColorTransform::new(&config.transform, strip.leds_count)?;
+    if config.socket != state.socket {
+        if !activated {
+            set_socket_permissions(&args.unix_socket, &config.socket)?;
+        }
+        log::info!("socket: {:?}", config.socket);
+        state.socket = config.socket;
+    }
+    if config.watchdog != state.watchdog_config {
+        log::info!("watchdog: {:?}", config.watchdog);
+        state.watchdog_config = config.watchdog;
+        // Apply a changed action right away.
+        if state.watchdog == Watchdog::Done {
+            state.watchdog = Watchdog::Fired;
+        }
+    }
+    if strip == state.strip && state.hw.is_some() {
+        log::info!("strip settings are unchanged");
+        if let Some(hw) = &mut state.hw {
+            hw.set_transform(transform.clone());
+        }
+        state.transform = transform;
+        return Ok(());
+    }
+    // The DMA channel has to be released before it can be claimed again.
+    if let Some(mut hw) = state.hw.take() {
+        if let Err(e) = hw.clear() {
+            log::warn!("couldn't blank the strip: {}", e);
+        }
+    }
+    match open_strip(&strip, &transform) {
+        Ok(hw) => {
+            log::info!("initialized strip with {:?}", strip);
+            state.hw = Some(hw);
+            state.strip = strip;
+            state.transform = transform;
+            return Ok(());
+        }
+        Err(e) => {
+            // Try to keep the lamp working with the previous settings.
+            state.hw = open_strip(&state.strip, &state.transform).ok();
+            return Err(e);
+        }
+    }
+}
+
+/// On SIGTERM or SIGINT, blanks the strip, releases the hardware and removes
+/// the socket before exiting. On SIGHUP, reloads the config file.
+/// A socket that was passed by systemd is left to systemd.
+fn handle_signals(state: Arc<Mutex<DaemonState>>, args: DaemonArgs, activated: bool) -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let mut state = lock(&state);
+            if signal == SIGHUP {
+                log::info!("reloading config");
+                systemd::notify_reloading();
+                if let Err(e) = reload(&mut state, &args, activated) {
+                    log::error!("couldn't reload config: {}", e);
+                }
+                systemd::notify_ready();
+                continue;
+            }
+            log::info!("received signal {}, shutting down", signal);
+            systemd::notify_stopping();
+            // Dropping the controller runs the ws281x cleanup. The lock is
+            // kept until the process exits, so no client can draw again.
+            if let Some(mut hw) = state.hw.take() {
+                if let Err(e) = hw.clear() {
+                    log::warn!("couldn't blank the strip: {}", e);
+                }
+            }
+            if !activated {
+                if let Err(e) = std::fs::remove_file(&args.unix_socket) {
+                    log::warn!("couldn't remove {}: {}", args.unix_socket, e);
+                }
+            }
+            std::process::exit(0);
+        }
+    });
+    return Ok(());
+}
+
 fn main() -> anyhow::Result<()> {
     let args = DaemonArgs::parse();
-    // It would be cleaner to delete this on shutdown using RAII,
-    // but rust doesn't unwind after signals so that cannot work.
-    if Path::new(&args.unix_socket).exists() {
+    logging::init(&args.log, Some(&args.instance_name))?;
+    let activated_listener = systemd::unix_listener()?;
+    // Remove a socket left behind by a daemon that didn't shut down cleanly.
+    if activated_listener.is_none() && Path::new(&args.unix_socket).exists() {
         std::fs::remove_file(&args.unix_socket)?;
     }
-    let handle = firelight::ledstrip::DeviceController::new(
-        args.dma,
-        args.channel,
-        args.pin,
-        args.leds_count,
-    )?;
-    let state = DaemonState { hw: handle };
+    let config = DaemonConfig::from_args(&args)?;
+    let strip = config.strip(&args)?;
+    let transform = ColorTransform::new(&config.transform, strip.leds_count)?;
+    let handle = open_strip(&strip, &transform)?;
+    let state = DaemonState {
+        hw: Some(handle),
+        strip: strip,
+        transform: transform,
+        watchdog_config: config.watchdog,
+        watchdog: Watchdog::Watching,
+        last_frame: Instant::now(),
+        last_colors: Vec::new(),
+        socket: config.socket.clone(),
+        stats: daemon::DaemonStats::default(),
+    };
     let shared_state = Arc::new(Mutex::new(state));
-    let listener = UnixListener::bind(&args.unix_socket)?;
-    std::fs::set_permissions(&args.unix_socket, Permissions::from_mode(0o666))?;
-    println!("listening on {}", args.unix_socket);
+    let watchdog_state = shared_state.clone();
+    thread::spawn(move || watchdog_thread(watchdog_state));
+    let activated = activated_listener.is_some();
+    let listener = match activated_listener {
+        // The socket unit takes care of the permissions.
+        Some(listener) => {
+            log::info!("listening on the socket passed by systemd");
+            listener
+        }
+        None => {
+            let listener = UnixListener::bind(&args.unix_socket)?;
+            set_socket_permissions(&args.unix_socket, &config.socket)?;
+            log::info!("listening on {}", args.unix_socket);
+            listener
+        }
+    };
+    handle_signals(shared_state.clone(), args, activated)?;
+    let alive_state = shared_state.clone();
+    systemd::spawn_watchdog(move || {
+        // Blocks if a client or the watchdog thread got stuck with the lock.
+        drop(lock(&alive_state));
+    });
+    systemd::notify_ready();
+    let mut next_client: u64 = 1;
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                let client = next_client;
+                next_client += 1;
+                // Checked here rather than only through the file permissions,
+                // since those don't apply to sockets passed by systemd.
+                let peer = match daemon::peer_credentials(&stream) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        log::warn!(client = client; "rejected client, couldn't query its credentials: {}", e);
+                        continue;
+                    }
+                };
+                // Not checked under the lock, since looking up the groups may take a while.
+                let socket = lock(&shared_state).socket.clone();
+                let groups = || {
+                    return daemon::user_groups(peer.uid, peer.gid).unwrap_or_else(|e| {
+                        log::warn!(client = client, uid = peer.uid; "couldn't look up the groups of the client: {}", e);
+                        vec![peer.gid]
+                    });
+                };
+                if !socket.allows(peer.uid, groups) {
+                    log::warn!(
+                        client = client, pid = peer.pid, uid = peer.uid, gid = peer.gid;
+                        "rejected client"
+                    );
+                    continue;
+                }
+                log::debug!(client = client, pid = peer.pid, uid = peer.uid; "new client");
                 let thread_state = shared_state.clone();
                 /* connection succeeded */
-                thread::spawn(move || handle_client(stream, thread_state));
+                thread::spawn(move || {
+                    match handle_client(stream, thread_state, client) {
+                        Ok(()) => log::debug!(client = client; "client disconnected"),
+                        Err(e) => log::warn!(client = client; "client failed: {}", e),
+                    }
+                });
             }
             Err(err) => {
-                println!("couldn't accept client: {}", err);
+                log::warn!("couldn't accept client: {}", err);
                 continue;
             }
         }
@@ -3,75 +3,1070 @@
 extern crate rouille;
 extern crate serde;
 
+use anyhow::anyhow;
+use chrono::DateTime;
+use chrono::Local;
+use chrono::NaiveTime;
+use chrono::TimeZone;
 use clap::Parser;
-use std::io;
-use std::os::unix::net::UnixStream;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::Read;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::net::TcpListener;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::path::PathBuf;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde::Serialize;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
 
 use firelight::Control;
+use firelight::Status;
+use firelight::WakeUp;
 use firelight::args::ServerArgs;
+use firelight::audio;
+use firelight::auth;
+use firelight::auth::Credentials;
+use firelight::auth::Denied;
+use firelight::config::AuthConfig;
+use firelight::config::ServerConfig;
+use firelight::daemon;
+use firelight::daemon::DaemonStats;
+use firelight::dmx;
+use firelight::layout::Layout;
+use firelight::logging;
+use firelight::metrics;
+use firelight::metrics::MetricKind;
+use firelight::metrics::Metrics;
+use firelight::opc;
+use firelight::openapi;
+use firelight::openapi::Operation;
+use firelight::preview::FrameJson;
+use firelight::preview::FrameStream;
+use firelight::presets::Preset;
+use firelight::presets::PresetStore;
+use firelight::renderer::ExternalInput;
+use firelight::schedule::Action;
+use firelight::schedule::Rule;
+use firelight::schedule::ScheduleStore;
+use firelight::sun::Location;
+use firelight::systemd;
+use firelight::wled;
 
+// The control page is compiled into the binary so that the server
+// can be deployed as a single file.
+const INDEX_HTML: &str = include_str!("../../static/index.html");
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, JsonSchema)]
 struct StatusResponse {
     on: bool,
     brightness: u8,
     effect: String,
     color_hs: (f32, f32),
+    speed: f32,
+
+    /// The last applied preset, as long as the state wasn't changed since.
+    preset: Option<String>,
+
+    /// When the running sleep timer will turn the lamp off, in RFC 3339 format.
+    sleep_until: Option<String>,
+
+    /// The pending or running wake-up light.
+    wake_up: Option<WakeUpResponse>,
+
+    /// Names of all built-in effects followed by the names of all presets,
+    /// so that Home Assistant can offer presets as effects.
+    effect_list: Vec<String>,
+
+    /// The network receiver whose frames are currently shown
+    /// instead of the rendered ones, e.g. `e1.31 from 10.0.0.5`.
+    external_source: Option<String>,
+
+    health: HealthResponse,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+struct HealthResponse {
+    /// False if frames currently can't be sent to the daemon.
+    daemon_connected: bool,
+
+    /// When the last frame reached the daemon, in RFC 3339 format.
+    last_frame: Option<String>,
+
+    write_errors: u64,
+    reconnects: u64,
+    last_error: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, JsonSchema)]
 struct AboutResponse {
     version: String,
     instance_name: String,
 }
 
+#[derive(Serialize, Debug, JsonSchema)]
+struct WakeUpResponse {
+    /// When the wake-up light reaches full brightness, in RFC 3339 format.
+    at: String,
+    minutes: f32,
+}
+
+/// Response of `GET /toggle` and `POST /toggle`.
+#[derive(Serialize, Debug, JsonSchema)]
+struct PowerResponse {
+    on: bool,
+}
+
+/// Request body of `PUT /brightness`, and response of the brightness endpoints.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+struct BrightnessBody {
+    brightness: u8,
+}
+
+/// Request body of `POST /brightness/step`.
+#[derive(Deserialize, Debug, JsonSchema)]
+struct BrightnessStepRequest {
+    /// Added to the current brightness, which stops at 0 and 255.
+    delta: i32,
+}
+
+/// Request body of `PUT /effect`, and response of the effect endpoints.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+struct EffectBody {
+    /// A built-in effect, or the name of a preset.
+    effect: String,
+}
+
+/// Request body of `PUT /color`, and response of the color endpoints.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+struct ColorBody {
+    /// Hue in [0.0, 360.0], Saturation in [0.0, 100.0]
+    color_hs: (f32, f32),
+}
+
+/// Request body of `POST /sleep`.
+#[derive(Deserialize, Debug, JsonSchema)]
+struct SleepRequest {
+    minutes: f32,
+}
+
+/// Request body of `PUT /wakeup`.
+#[derive(Deserialize, Debug, JsonSchema)]
+struct WakeUpRequest {
+    /// Either a time of day like "07:30", meaning the next time the clock
+    /// shows that time, or a full date and time in RFC 3339 format.
+    at: String,
+
+    /// Duration of the sunrise simulation.
+    #[serde(default = "default_wake_up_minutes")]
+    minutes: f32,
+}
+
+fn default_wake_up_minutes() -> f32 {
+    return 30.0;
+}
+
+fn to_rfc3339(time: SystemTime) -> String {
+    return DateTime::<Local>::from(time).to_rfc3339();
+}
+
+/// Parses the time at which a wake-up light should end, see `WakeUpRequest`.
+fn parse_wake_up_time(text: &str, now: DateTime<Local>) -> anyhow::Result<SystemTime> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.into());
+    }
+    let time_of_day = NaiveTime::parse_from_str(text, "%H:%M")
+        .map_err(|_| anyhow!("expected a time like '07:30' or an RFC 3339 timestamp"))?;
+    let mut date = now.date_naive();
+    loop {
+        // Times that don't exist due to daylight saving are skipped.
+        if let Some(time) = Local.from_local_datetime(&date.and_time(time_of_day)).earliest() {
+            if time > now {
+                return Ok(time.into());
+            }
+        }
+        date = date.succ_opt().ok_or_else(|| anyhow!("date out of range"))?;
+    }
+}
+
+/// Body of error responses.
+#[derive(Serialize, Debug, JsonSchema)]
+struct ErrorResponse {
+    /// The request field that was rejected, if the error is about a single one.
+    field: Option<String>,
+
+    reason: String,
+
+    /// All accepted values, if the field only accepts a fixed set.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    valid_values: Vec<String>,
+}
+
+impl ErrorResponse {
+    fn new(reason: impl ToString) -> ErrorResponse {
+        return ErrorResponse {
+            field: None,
+            reason: reason.to_string(),
+            valid_values: Vec::new(),
+        };
+    }
+
+    fn field(field: &str, reason: impl ToString) -> ErrorResponse {
+        return ErrorResponse {
+            field: Some(field.to_string()),
+            ..ErrorResponse::new(reason)
+        };
+    }
+
+    fn response(&self, status_code: u16) -> rouille::Response {
+        return rouille::Response::json(self).with_status_code(status_code);
+    }
+}
+
+/// A change of the state failed. If only the daemon is unavailable, the
+/// state was accepted but can't be shown on the lamp right now.
+fn unavailable(error: anyhow::Error) -> rouille::Response {
+    if error.is::<firelight::DaemonUnavailable>() {
+        log::warn!("{}", error);
+        return ErrorResponse::new(error).response(503);
+    }
+    log::error!("{}", error);
+    return ErrorResponse::new(error).response(500);
+}
+
+/// A request handler panicked while holding the lock.
+fn poisoned<T>(error: T) -> rouille::Response
+where
+    T: Display,
+{
+    log::error!("shared state is poisoned: {}", error);
+    return ErrorResponse::new("the server is in an inconsistent state, please restart it").response(500);
+}
+
+/// Rejects an `effect` that is neither a built-in effect nor a preset.
+fn invalid_effect(name: &str, presets: &PresetStore) -> ErrorResponse {
+    let effects = firelight::Effect::all().iter().map(|effect| effect.to_string());
+    let names = presets.list().iter().map(|preset| preset.name.clone());
+    return ErrorResponse {
+        valid_values: effects.chain(names).collect(),
+        ..ErrorResponse::field("effect", format!("unknown effect '{}'", name))
+    };
+}
+
+fn parse_number<T>(field: &str, text: &str) -> Result<T, ErrorResponse>
+where
+    T: FromStr,
+{
+    return text.trim()
+        .parse()
+        .map_err(|_| ErrorResponse::field(field, format!("'{}' is not a valid number", text)));
+}
+
+/// Parses a JSON request body. Unlike `rouille::input::json_input`, this
+/// doesn't require a JSON `Content-Type`, which e.g. curl doesn't send by
+/// default, and the error says what is wrong with the body, e.g. which field
+/// has an invalid value.
+fn json_body<T: DeserializeOwned>(request: &rouille::Request) -> Result<T, String> {
+    let body = request.data().ok_or_else(|| "the request body was already read".to_string())?;
+    return serde_json::from_reader(body).map_err(|e| format!("invalid request body: {}", e));
+}
+
+/// Parses a number from a form field and checks that it lies in `range`.
+fn parse_field<T>(field: &str, text: &str, range: RangeInclusive<T>) -> Result<T, ErrorResponse>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let value: T = parse_number(field, text)?;
+    if !range.contains(&value) {
+        return Err(ErrorResponse::field(field, format!("must be in [{}, {}]", range.start(), range.end())));
+    }
+    return Ok(value);
+}
+
+/// Checks that hue and saturation are in their documented ranges.
+fn check_color_hs(color_hs: (f32, f32)) -> Result<(f32, f32), ErrorResponse> {
+    firelight::validate_color_hs(color_hs).map_err(|e| ErrorResponse::field("color_hs", e))?;
+    return Ok(color_hs);
+}
+
+/// Form fields of `POST /control`, in the format sent by Home Assistant.
+/// Only `on` is mandatory, the other fields keep their current value.
+#[derive(Debug, JsonSchema)]
+struct ControlForm {
+    /// "True" or "False".
+    on: String,
+    /// Integer in [0, 255].
+    brightness: Option<String>,
+    /// Either empty, or hue in [0.0, 360.0] and saturation in
+    /// [0.0, 100.0] as two fields with the same name.
+    #[schemars(default)]
+    color_hs: Vec<String>,
+    /// A built-in effect or the name of a preset.
+    effect: Option<String>,
+    /// Number in [0.0, 10.0].
+    speed: Option<String>,
+}
+
+impl ControlForm {
+    /// Applies the form to the `current` state. If `effect` names a preset,
+    /// the other fields are applied on top of the preset, which is returned too.
+    fn apply(&self, current: Control, presets: &PresetStore) -> Result<(Control, Option<Preset>), ErrorResponse> {
+        let mut control = current;
+        let preset = self.effect.as_ref()
+            .and_then(|name| presets.get(name))
+            .cloned();
+        if let Some(preset) = &preset {
+            control = preset.control;
+        }
+        control.on = match self.on.as_str() {
+            "True" => true,
+            "False" => false,
+            _ => return Err(ErrorResponse {
+                valid_values: vec!["True".to_string(), "False".to_string()],
+                ..ErrorResponse::field("on", format!("invalid value '{}'", self.on))
+            }),
+        };
+        if let Some(brightness) = &self.brightness {
+            // Parsed as a wider type, so that e.g. 300 is reported as out of range.
+            control.brightness = parse_field::<i64>("brightness", brightness, 0..=255)? as u8;
+        }
+        if let (Some(effect), None) = (&self.effect, &preset) {
+            control.effect = firelight::Effect::from_string(effect)
+                .map_err(|_| invalid_effect(effect, presets))?;
+        }
+        if let Some(speed) = &self.speed {
+            control.speed = parse_field("speed", speed, firelight::SPEED_RANGE)?;
+        }
+        match self.color_hs.as_slice() {
+            [] => (),
+            [hue, saturation] => {
+                control.color_hs = check_color_hs((
+                    parse_number("color_hs", hue)?,
+                    parse_number("color_hs", saturation)?,
+                ))?;
+            },
+            values => {
+                let reason = format!("expected hue and saturation, got {} values", values.len());
+                return Err(ErrorResponse::field("color_hs", reason));
+            },
+        }
+        return Ok((control, preset));
+    }
+}
+
+fn minutes(minutes: f32) -> anyhow::Result<Duration> {
+    if !minutes.is_finite() || minutes <= 0.0 || minutes > 24.0 * 60.0 {
+        return Err(anyhow!("'minutes' must be in (0, 1440]"));
+    }
+    return Ok(Duration::from_secs_f32(minutes * 60.0));
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+struct RuleResponse {
+    #[serde(flatten)]
+    rule: Rule,
+
+    /// The next time at which the rule fires, in RFC 3339 format.
+    next: Option<String>,
+}
+
+impl RuleResponse {
+    fn new(rule: &Rule, schedule: &ScheduleStore) -> RuleResponse {
+        return RuleResponse {
+            rule: rule.clone(),
+            next: schedule.next(rule, Local::now()).map(|time| time.to_rfc3339()),
+        };
+    }
+}
+
 impl StatusResponse {
-    fn from_control(control: Control) -> StatusResponse {
+    fn from_state(state: &ServerState) -> StatusResponse {
+        let status = state.firelight.status();
+        let control = status.control;
+        let effects = firelight::Effect::all().iter().map(|effect| effect.to_string());
+        let presets = state.presets.list().iter().map(|preset| preset.name.clone());
         return StatusResponse {
             on: control.on,
             brightness: control.brightness,
             effect: control.effect.to_string(),
             color_hs: control.color_hs,
+            speed: control.speed,
+            preset: state.active_preset.clone(),
+            sleep_until: status.sleep_until.map(to_rfc3339),
+            wake_up: status.wake_up.map(|wake_up| WakeUpResponse {
+                at: to_rfc3339(wake_up.at),
+                minutes: wake_up.duration.as_secs_f32() / 60.0,
+            }),
+            effect_list: effects.chain(presets).collect(),
+            external_source: status.external_source,
+            health: HealthResponse {
+                daemon_connected: status.health.daemon_connected,
+                last_frame: status.health.last_frame.map(to_rfc3339),
+                write_errors: status.health.write_errors,
+                reconnects: status.health.reconnects,
+                last_error: status.health.last_error,
+            },
         };
     }
 }
 
 struct ServerState {
-    last_state: Control,
+    active_preset: Option<String>,
+    presets: PresetStore,
+    schedule: ScheduleStore,
     firelight: firelight::Handle,
 }
 
 impl ServerState {
-    fn new(socket: UnixStream, strands: Vec<usize>) -> ServerState {
-        let handle = firelight::Handle::new(socket, strands);
-        return ServerState {
-            last_state: Control::default(),
+    fn new(socket_path: &Path, strands: Vec<usize>, presets: PresetStore, schedule: ScheduleStore) -> anyhow::Result<ServerState> {
+        let handle = firelight::Handle::new(socket_path, strands)?;
+        return Ok(ServerState {
+            active_preset: None,
+            presets: presets,
+            schedule: schedule,
             firelight: handle,
+        });
+    }
+
+    fn control(&mut self, control: Control) -> anyhow::Result<()> {
+        self.active_preset = None;
+        return self.firelight.control(control);
+    }
+
+    fn apply_preset(&mut self, preset: &Preset) -> anyhow::Result<()> {
+        self.active_preset = Some(preset.name.clone());
+        return self.firelight.apply_preset(preset);
+    }
+
+    fn run_action(&mut self, action: &Action) -> anyhow::Result<()> {
+        return match action {
+            Action::Control(control) => self.control(*control),
+            Action::Preset(name) => {
+                let preset = self.presets.get(name).cloned().ok_or_else(|| anyhow!("no preset named '{}'", name))?;
+                self.apply_preset(&preset)
+            },
+            Action::Off => {
+                let mut control = self.firelight.state();
+                control.on = false;
+                self.control(control)
+            },
         };
     }
+
+    /// Rejects invalid rules and rules that refer to presets that don't exist.
+    fn check_rule(&self, rule: &Rule) -> anyhow::Result<()> {
+        self.schedule.validate(rule)?;
+        match &rule.action {
+            Action::Control(control) => control.validate()?,
+            Action::Preset(name) => {
+                if self.presets.get(name).is_none() {
+                    return Err(anyhow!("no preset named '{}'", name));
+                }
+            },
+            Action::Off => (),
+        }
+        return Ok(());
+    }
+}
+
+/// Fades out the lamp before exiting on SIGTERM or SIGINT, so that stopping
+/// the service doesn't leave the LEDs frozen on their last frame.
+fn shutdown_on_signals(server_state: Arc<Mutex<ServerState>>) -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            log::info!("received signal {}, shutting down", signal);
+            // Keep the lock until the process exits, so that
+            // no request can turn the lamp on again meanwhile.
+            let mut state = server_state.lock().unwrap_or_else(|e| e.into_inner());
+            state.firelight.shutdown();
+            std::process::exit(0);
+        }
+    });
+    return Ok(());
+}
+
+/// The metrics served at `/metrics`, for the current `status` and `colors`
+/// of the latest frame. The daemon stats are left out if the query failed.
+fn metrics_text(status: &Status, colors: &[u32], daemon_stats: anyhow::Result<DaemonStats>) -> String {
+    let mut metrics = Metrics::new();
+    let control = &status.control;
+    let health = &status.health;
+    metrics.add("firelight_render_fps", MetricKind::Gauge, "Frames per second sent to the strip, rendered or external.", status.render.fps as f64);
+    metrics.add("firelight_frames_total", MetricKind::Counter, "Frames sent to the strip, rendered or external.", status.render.frames as f64);
+    metrics.add_summary(
+        "firelight_render_duration_seconds",
+        "Time spent computing the frames that weren't external.",
+        status.render.render_time.as_secs_f64(),
+        status.render.rendered,
+    );
+    metrics.add("firelight_daemon_connected", MetricKind::Gauge, "Whether frames currently reach the daemon.", health.daemon_connected as u8 as f64);
+    metrics.add("firelight_daemon_write_errors_total", MetricKind::Counter, "Failed attempts to send a frame to the daemon or to reconnect.", health.write_errors as f64);
+    metrics.add("firelight_daemon_reconnects_total", MetricKind::Counter, "How often the connection to the daemon was re-established.", health.reconnects as f64);
+    metrics.add("firelight_on", MetricKind::Gauge, "Whether the lamp is on.", control.on as u8 as f64);
+    metrics.add("firelight_brightness", MetricKind::Gauge, "Brightness in [0, 255].", control.brightness as f64);
+    let effects: Vec<(String, f64)> = firelight::Effect::all()
+        .iter()
+        .map(|effect| (effect.to_string(), (*effect == control.effect) as u8 as f64))
+        .collect();
+    metrics.add_labeled("firelight_effect", MetricKind::Gauge, "1 for the selected effect, 0 for all others.", "effect", &effects);
+    metrics.add("firelight_estimated_power_watts", MetricKind::Gauge, "Estimated power drawn by the strip for the latest frame.", metrics::estimated_watts(colors));
+    metrics.add("firelight_daemon_stats_available", MetricKind::Gauge, "Whether the daemon answered the stats query.", daemon_stats.is_ok() as u8 as f64);
+    if let Ok(stats) = daemon_stats {
+        metrics.add("firelight_daemon_frames_applied_total", MetricKind::Counter, "Frames written to the strip by the daemon.", stats.frames_applied as f64);
+        metrics.add("firelight_daemon_frames_dropped_total", MetricKind::Counter, "Frames dropped by the daemon because the strip wasn't initialized.", stats.frames_dropped as f64);
+        metrics.add_summary(
+            "firelight_daemon_render_duration_seconds",
+            "Time the daemon spent writing frames to the strip.",
+            stats.render_seconds_total,
+            stats.frames_applied,
+        );
+        metrics.add("firelight_daemon_max_render_duration_seconds", MetricKind::Gauge, "Longest time the daemon spent writing a single frame.", stats.max_render_seconds);
+    }
+    return metrics.finish();
+}
+
+/// Checks the credentials of a request against the `[auth]` section of the
+/// config file. Returns the response to send instead if they don't suffice.
+fn check_auth(config: &AuthConfig, request: &rouille::Request) -> Option<rouille::Response> {
+    let bearer = request.header("Authorization").and_then(auth::bearer_token);
+    let basic = rouille::input::basic_http_auth(request);
+    let credentials = match (bearer, &basic) {
+        (Some(token), _) => Some(Credentials::Bearer(token)),
+        (None, Some(basic)) => Some(Credentials::Basic { user: &basic.login, password: &basic.password }),
+        (None, None) => None,
+    };
+    return match auth::authorize(config, request.method(), &request.url(), credentials) {
+        Ok(()) => None,
+        // Let browsers ask for a password, unless only tokens are configured.
+        Err(Denied::Unauthenticated) if !config.users.is_empty() => {
+            Some(rouille::Response::basic_http_auth_login_required("firelight"))
+        },
+        Err(Denied::Unauthenticated) => Some(
+            rouille::Response::text("authentication required")
+                .with_status_code(401)
+                .with_additional_header("WWW-Authenticate", "Bearer realm=\"firelight\""),
+        ),
+        Err(Denied::Forbidden) => Some(
+            rouille::Response::text("these credentials only allow reading the state").with_status_code(403),
+        ),
+    };
+}
+
+/// Reads the certificate and private key if TLS is configured.
+fn read_tls(config: &ServerConfig) -> anyhow::Result<Option<tiny_http::SslConfig>> {
+    let tls = match &config.tls {
+        Some(tls) => tls,
+        None => return Ok(None),
+    };
+    if !cfg!(feature = "tls") {
+        return Err(anyhow!("TLS is configured, but firelight-rest was built without the 'tls' feature"));
+    }
+    let read = |path: &str| std::fs::read(path).map_err(|e| anyhow!("couldn't read {}: {}", path, e));
+    return Ok(Some(tiny_http::SslConfig {
+        certificate: read(&tls.certificate)?,
+        private_key: read(&tls.private_key)?,
+    }));
+}
+
+/// Starts a thread for each of the E1.31 and Art-Net receivers that are enabled.
+fn spawn_dmx_receivers(args: &ServerArgs, layout: &Layout, input: &ExternalInput) -> anyhow::Result<()> {
+    let receivers = [(&args.e131_bind, dmx::Protocol::E131), (&args.artnet_bind, dmx::Protocol::ArtNet)];
+    for (bind, protocol) in receivers {
+        let bind = match bind {
+            Some(bind) => bind,
+            None => continue,
+        };
+        let socket = UdpSocket::bind(bind).map_err(|e| anyhow!("couldn't listen on {}: {}", bind, e))?;
+        let universe = args.dmx_universe.unwrap_or_else(|| protocol.first_universe());
+        let mapping = dmx::Mapping::new(layout, universe, args.dmx_channel, args.dmx_universe_per_strand)?;
+        let input = input.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = dmx::receive(socket, protocol, mapping, input) {
+                log::error!("{:?} receiver stopped: {}", protocol, e);
+            }
+        });
+    }
+    return Ok(());
+}
+
+/// Starts the Open Pixel Control server if it's enabled.
+fn spawn_opc_server(args: &ServerArgs, layout: &Layout, input: &ExternalInput) -> anyhow::Result<()> {
+    let bind = match &args.opc_bind {
+        Some(bind) => bind,
+        None => return Ok(()),
+    };
+    let listener = TcpListener::bind(bind).map_err(|e| anyhow!("couldn't listen on {}: {}", bind, e))?;
+    let layout = layout.clone();
+    let input = input.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = opc::serve(listener, &layout, input) {
+            log::error!("OPC server stopped: {}", e);
+        }
+    });
+    return Ok(());
+}
+
+/// Starts the receiver for WLED realtime packets if it's enabled,
+/// and returns its port, or 0 if it isn't.
+fn spawn_wled_receiver(args: &ServerArgs, input: &ExternalInput) -> anyhow::Result<u16> {
+    let bind = match &args.wled_udp_bind {
+        Some(bind) => bind,
+        None => return Ok(0),
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| anyhow!("couldn't listen on {}: {}", bind, e))?;
+    let port = socket.local_addr()?.port();
+    let input = input.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = wled::receive(socket, input) {
+            log::error!("WLED realtime receiver stopped: {}", e);
+        }
+    });
+    return Ok(port);
+}
+
+/// The state in the format of WLED's JSON API.
+fn wled_state(state: &ServerState) -> wled::State {
+    let active = state.active_preset.as_ref().and_then(|name| state.presets.get(name)).map(|preset| preset.id);
+    return wled::State::new(&state.firelight.status(), state.firelight.external_input().len(), active, SystemTime::now());
+}
+
+/// Applies a change of the state from a WLED client. A preset is applied
+/// first, so that the other fields can change it.
+fn apply_wled_update(state: &mut ServerState, update: &wled::StateUpdate) -> Result<(), rouille::Response> {
+    if update.live == Some(false) {
+        state.firelight.external_input().stop();
+    }
+    let mut result = Ok(());
+    if let Some(id) = update.ps {
+        let preset = match state.presets.get_by_id(id) {
+            Some(preset) => preset.clone(),
+            None => return Err(ErrorResponse::field("ps", format!("no preset with id {}", id)).response(400)),
+        };
+        result = state.apply_preset(&preset);
+    }
+    let current = state.firelight.state();
+    let control = update.control(current).map_err(|e| ErrorResponse::new(e).response(400))?;
+    if control != current {
+        result = state.control(control);
+    }
+    if let Some(nightlight) = &update.nl {
+        match nightlight.on {
+            // WLED's default duration is an hour.
+            Some(true) => result = state.firelight.sleep_timer(Duration::from_secs(nightlight.dur.unwrap_or(60) as u64 * 60)),
+            Some(false) => result = state.firelight.cancel_sleep_timer(),
+            None => (),
+        }
+    }
+    return result.map_err(unavailable);
+}
+
+fn handle_wled_update(server_state: &Mutex<ServerState>, request: &rouille::Request) -> rouille::Response {
+    let update: wled::StateUpdate = match json_body(request) {
+        Ok(update) => update,
+        Err(e) => return ErrorResponse::new(e).response(400),
+    };
+    let mut state = match server_state.lock() {
+        Ok(state) => state,
+        Err(e) => return poisoned(e),
+    };
+    if let Err(response) = apply_wled_update(&mut state, &update) {
+        return response;
+    }
+    if update.v {
+        return rouille::Response::json(&wled_state(&state));
+    }
+    return rouille::Response::json(&serde_json::json!({ "success": true }));
+}
+
+/// Runs the handler and logs the request with the client address, the status
+/// code and how long it took. Panics are logged and then passed on to rouille.
+fn log_request<F>(request: &rouille::Request, handler: F) -> rouille::Response
+where
+    F: FnOnce() -> rouille::Response,
+{
+    let started = Instant::now();
+    let client = request.remote_addr().to_string();
+    let result = std::panic::catch_unwind(AssertUnwindSafe(handler));
+    let millis = started.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(response) => {
+            log::info!(
+                client = client, method = request.method(), url = request.raw_url(),
+                status = response.status_code, millis = format!("{:.2}", millis);
+                "{} {} {}", request.method(), request.raw_url(), response.status_code
+            );
+            return response;
+        },
+        Err(panic) => {
+            log::error!(
+                client = client, method = request.method(), url = request.raw_url();
+                "{} {} panicked", request.method(), request.raw_url()
+            );
+            std::panic::resume_unwind(panic);
+        },
+    }
+}
+
+/// Periodically checks which rules of the schedule are due and runs their actions.
+fn scheduler_thread(server_state: Arc<Mutex<ServerState>>) {
+    let mut last_check = Local::now();
+    loop {
+        std::thread::sleep(Duration::from_secs(5));
+        let now = Local::now();
+        let mut state = match server_state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                log::error!("scheduler: shared state is poisoned: {}", e);
+                return;
+            }
+        };
+        for rule in state.schedule.due(last_check, now) {
+            log::info!(rule = rule.id; "scheduler: running rule '{}'", rule.name);
+            if let Err(e) = state.run_action(&rule.action) {
+                log::warn!(rule = rule.id; "scheduler: rule failed: {}", e);
+            }
+        }
+        last_check = now;
+    }
+}
+
+/// Serves requests with tiny_http, which rouille is built on, and hands each
+/// of them to the rouille handler. Unlike `rouille::Server`, this can serve a
+/// socket passed by systemd, and it sends bodies without a length, like the
+/// one of `/frame/stream`, with `stream_response()`.
+fn serve<F>(listener: TcpListener, handler: F, tls: Option<tiny_http::SslConfig>) -> anyhow::Result<()>
+where
+    F: Fn(&rouille::Request) -> rouille::Response + Send + Sync + 'static,
+{
+    let server = tiny_http::Server::from_listener(listener, tls).map_err(|e| anyhow!("{}", e))?;
+    let handler = Arc::new(handler);
+    for mut request in server.incoming_requests() {
+        let handler = handler.clone();
+        // Like rouille, use a thread per request, since
+        // `/frame/stream` keeps its request open.
+        std::thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Err(e) = request.as_reader().read_to_end(&mut data) {
+                log::warn!("couldn't read request: {}", e);
+                return;
+            }
+            let headers = request.headers()
+                .iter()
+                .map(|header| (header.field.as_str().to_string(), header.value.to_string()))
+                .collect();
+            let remote_addr = request.remote_addr().copied().unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+            let method = request.method().as_str().to_string();
+            let url = request.url().to_string();
+            let rouille_request = if request.secure() {
+                rouille::Request::fake_https_from(remote_addr, method, url, headers, data)
+            } else {
+                rouille::Request::fake_http_from(remote_addr, method, url, headers, data)
+            };
+            let response = handler(&rouille_request);
+            let (reader, length) = response.data.into_reader_and_size();
+            if length.is_none() {
+                if let Err(e) = stream_response(request, response.status_code, &response.headers, reader) {
+                    log::debug!("streaming response ended: {}", e);
+                }
+                return;
+            }
+            let headers = response.headers
+                .iter()
+                .filter_map(|(field, value)| tiny_http::Header::from_bytes(field.as_bytes(), value.as_bytes()).ok())
+                .collect();
+            let response = tiny_http::Response::new(response.status_code.into(), headers, reader, length, None);
+            if let Err(e) = request.respond(response) {
+                log::warn!("couldn't send response: {}", e);
+            }
+        });
+    }
+    return Ok(());
+}
+
+/// Sends a response with chunked encoding, where every read from `body` is
+/// sent as a chunk right away. tiny_http would collect the body in chunks of
+/// 8KB, so that e.g. the events of `/frame/stream` would arrive in bursts.
+fn stream_response(
+    request: tiny_http::Request,
+    status_code: u16,
+    headers: &[(Cow<'static, str>, Cow<'static, str>)],
+    mut body: impl Read,
+) -> std::io::Result<()> {
+    let mut writer = request.into_writer();
+    let reason = tiny_http::StatusCode(status_code).default_reason_phrase();
+    write!(writer, "HTTP/1.1 {} {}\r\n", status_code, reason)?;
+    for (field, value) in headers {
+        write!(writer, "{}: {}\r\n", field, value)?;
+    }
+    write!(writer, "Transfer-Encoding: chunked\r\n\r\n")?;
+    writer.flush()?;
+    let mut buffer = vec![0u8; 8192];
+    loop {
+        let n = body.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        write!(writer, "{:x}\r\n", n)?;
+        writer.write_all(&buffer[..n])?;
+        write!(writer, "\r\n")?;
+        writer.flush()?;
+    }
+    write!(writer, "0\r\n\r\n")?;
+    return writer.flush();
+}
+
+/// Describes all routes for the OpenAPI document. The bodies are described
+/// by their types, and the routes are checked against the router in `main`
+/// by `tests::openapi_describes_all_routes`.
+fn api_operations(auth: &AuthConfig) -> Vec<Operation> {
+    let status_operations = [
+        Operation::new("GET", "/status", "The current state of the lamp.")
+            .json_response::<StatusResponse>("The current state."),
+        Operation::new("GET", "/about", "Version and name of this server.")
+            .json_response::<AboutResponse>("Information about the server."),
+    ];
+    let status_operations = status_operations
+        .into_iter()
+        .map(|operation| if auth.anonymous_status { operation.anonymous() } else { operation });
+    let operations = [
+        Operation::new("GET", "/", "The control page for browsers.")
+            .response(200, "text/html", "The control page."),
+        Operation::new("GET", "/openapi.json", "This document.")
+            .response(200, "application/json", "The OpenAPI document."),
+        Operation::new("GET", "/metrics", "Metrics of the renderer and the daemon for Prometheus.")
+            .response(200, "text/plain", "Metrics in the Prometheus text format."),
+        Operation::new("GET", "/frame", "The most recently rendered frame.")
+            .query_param::<String>("format", "Either `json` (default) or `png`.")
+            .query_param::<usize>("scale", "Size of an LED in pixels for `png`, in [1, 64], 8 by default.")
+            .json_response::<FrameJson>("The frame.")
+            .response(200, "image/png", "The frame, with one column per strand.")
+            .json_status::<ErrorResponse>(400, "Invalid format.")
+            .json_status::<ErrorResponse>(409, "A `png` was requested, but the layout has no LEDs."),
+        Operation::new("GET", "/frame/stream", "A stream of frames as server-sent events.")
+            .query_param::<u32>("fps", "Frames per second, 10 by default.")
+            .response(200, "text/event-stream", "Frames in the same format as `/frame`."),
+        Operation::new("POST", "/control", "Set the state, using the fields sent by Home Assistant.")
+            .form_body::<ControlForm>()
+            .response(200, "text/plain", "The state was changed.")
+            .json_status::<ErrorResponse>(400, "A field is missing or invalid.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("GET", "/toggle", "Whether the lamp is on.")
+            .json_response::<PowerResponse>("Whether the lamp is on."),
+        Operation::new("POST", "/toggle", "Turn the lamp on or off.")
+            .json_response::<PowerResponse>("Whether the lamp is on now.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("GET", "/brightness", "The current brightness.")
+            .json_response::<BrightnessBody>("The brightness."),
+        Operation::new("PUT", "/brightness", "Set the brightness.")
+            .json_body::<BrightnessBody>()
+            .json_response::<BrightnessBody>("The new brightness.")
+            .json_status::<ErrorResponse>(400, "Invalid request body.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("POST", "/brightness/step", "Change the brightness relative to the current value.")
+            .json_body::<BrightnessStepRequest>()
+            .json_response::<BrightnessBody>("The new brightness.")
+            .json_status::<ErrorResponse>(400, "Invalid request body.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("GET", "/effect", "The current effect.")
+            .json_response::<EffectBody>("The effect."),
+        Operation::new("PUT", "/effect", "Select an effect or apply a preset.")
+            .json_body::<EffectBody>()
+            .json_response::<EffectBody>("The new effect.")
+            .json_status::<ErrorResponse>(400, "Unknown effect, the error lists the valid ones.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("GET", "/color", "The current color.")
+            .json_response::<ColorBody>("The color."),
+        Operation::new("PUT", "/color", "Set the color.")
+            .json_body::<ColorBody>()
+            .json_response::<ColorBody>("The new color.")
+            .json_status::<ErrorResponse>(400, "Hue or saturation out of range.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("POST", "/sleep", "Fade out and turn off over the given time.")
+            .json_body::<SleepRequest>()
+            .response(200, "text/plain", "The sleep timer was started.")
+            .json_status::<ErrorResponse>(400, "Invalid duration.")
+            .json_status::<ErrorResponse>(503, "The timer was accepted, but the daemon is unavailable."),
+        Operation::new("DELETE", "/sleep", "Cancel the sleep timer.")
+            .empty_response(204, "The sleep timer was cancelled.")
+            .json_status::<ErrorResponse>(503, "The daemon is unavailable."),
+        Operation::new("PUT", "/wakeup", "Schedule a wake-up light.")
+            .json_body::<WakeUpRequest>()
+            .json_response::<WakeUpResponse>("The scheduled wake-up light.")
+            .json_status::<ErrorResponse>(400, "Invalid time or duration.")
+            .json_status::<ErrorResponse>(503, "The wake-up light was accepted, but the daemon is unavailable."),
+        Operation::new("DELETE", "/wakeup", "Cancel the wake-up light.")
+            .empty_response(204, "The wake-up light was cancelled.")
+            .json_status::<ErrorResponse>(503, "The daemon is unavailable."),
+        Operation::new("GET", "/presets", "All presets.")
+            .json_response::<Vec<Preset>>("All presets, in the order they were created."),
+        Operation::new("GET", "/presets/{name}", "A single preset.")
+            .path_param::<String>("name", "Name of the preset.")
+            .json_response::<Preset>("The preset.")
+            .empty_response(404, "No such preset."),
+        Operation::new("PUT", "/presets/{name}", "Store the given state as a preset.")
+            .path_param::<String>("name", "Name of the preset.")
+            .json_body::<Control>()
+            .json_response::<Preset>("The stored preset.")
+            .json_status::<ErrorResponse>(400, "Invalid state or name.")
+            .json_status::<ErrorResponse>(500, "The presets file couldn't be written."),
+        Operation::new("POST", "/presets/{name}", "Store the current state as a preset.")
+            .path_param::<String>("name", "Name of the preset.")
+            .json_response::<Preset>("The stored preset.")
+            .json_status::<ErrorResponse>(400, "Invalid name.")
+            .json_status::<ErrorResponse>(500, "The presets file couldn't be written."),
+        Operation::new("POST", "/presets/{name}/apply", "Apply a preset.")
+            .path_param::<String>("name", "Name of the preset.")
+            .response(200, "text/plain", "The preset was applied.")
+            .empty_response(404, "No such preset.")
+            .json_status::<ErrorResponse>(503, "The preset was accepted, but the daemon is unavailable."),
+        Operation::new("DELETE", "/presets/{name}", "Remove a preset.")
+            .path_param::<String>("name", "Name of the preset.")
+            .empty_response(204, "The preset was removed.")
+            .empty_response(404, "No such preset.")
+            .json_status::<ErrorResponse>(500, "The presets file couldn't be written."),
+        Operation::new("GET", "/json", "State and info in the format of WLED's JSON API.")
+            .json_response::<wled::Full>("State, info, and the names of effects and palettes."),
+        Operation::new("GET", "/json/state", "The state in the format of WLED's JSON API.")
+            .json_response::<wled::State>("The state, with the strip as a single segment."),
+        Operation::new("POST", "/json/state", "Change the state like WLED.")
+            .json_body::<wled::StateUpdate>()
+            .json_response::<wled::State>("The new state, if `v` was set. Otherwise `{\"success\": true}`.")
+            .json_status::<ErrorResponse>(400, "Invalid request body.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("POST", "/json", "The same as `POST /json/state`.")
+            .json_body::<wled::StateUpdate>()
+            .json_response::<wled::State>("The new state, if `v` was set. Otherwise `{\"success\": true}`.")
+            .json_status::<ErrorResponse>(400, "Invalid request body.")
+            .json_status::<ErrorResponse>(503, "The state was accepted, but the daemon is unavailable."),
+        Operation::new("GET", "/json/info", "Information about the lamp in the format of WLED's JSON API.")
+            .json_response::<wled::Info>("The information."),
+        Operation::new("GET", "/json/eff", "Names of the effects, indexed by WLED effect id.")
+            .json_response::<Vec<String>>("The names."),
+        Operation::new("GET", "/json/pal", "Names of the palettes, of which there is only the default one.")
+            .json_response::<Vec<String>>("The names."),
+        Operation::new("GET", "/presets.json", "The presets in the format of WLED, keyed by their id.")
+            .response(200, "application/json", "The presets."),
+        Operation::new("GET", "/schedule", "All schedule rules.")
+            .json_response::<Vec<RuleResponse>>("All rules."),
+        Operation::new("POST", "/schedule", "Add a schedule rule.")
+            .json_body::<Rule>()
+            .json_response::<RuleResponse>("The new rule with its id.")
+            .json_status::<ErrorResponse>(400, "Invalid rule.")
+            .json_status::<ErrorResponse>(500, "The schedule file couldn't be written."),
+        Operation::new("GET", "/schedule/{id}", "A single schedule rule.")
+            .path_param::<u32>("id", "Id of the rule.")
+            .json_response::<RuleResponse>("The rule.")
+            .empty_response(404, "No such rule."),
+        Operation::new("PUT", "/schedule/{id}", "Replace a schedule rule.")
+            .path_param::<u32>("id", "Id of the rule.")
+            .json_body::<Rule>()
+            .json_response::<RuleResponse>("The new rule.")
+            .empty_response(404, "No such rule.")
+            .json_status::<ErrorResponse>(400, "Invalid rule.")
+            .json_status::<ErrorResponse>(500, "The schedule file couldn't be written."),
+        Operation::new("DELETE", "/schedule/{id}", "Remove a schedule rule.")
+            .path_param::<u32>("id", "Id of the rule.")
+            .empty_response(204, "The rule was removed.")
+            .empty_response(404, "No such rule.")
+            .json_status::<ErrorResponse>(500, "The schedule file couldn't be written."),
+    ];
+    return status_operations.chain(operations).collect();
 }
 
 fn main() -> anyhow::Result<()> {
     let firelight_version: &str = option_env!("CARGO_PKG_VERSION").unwrap_or("unknown");
     let args = ServerArgs::parse();
-    let uds = UnixStream::connect(args.daemon_socket)?;
-    print!("starting server listening on {}\n", args.bind);
-    let server_state = Mutex::new(ServerState::new(uds, args.strands));
+    logging::init(&args.log, Some(&args.instance_name))?;
+    let config = ServerConfig::from_args(&args)?;
+    let tls = read_tls(&config)?;
+    if !auth::is_enabled(&config.auth) {
+        log::warn!("no credentials configured, the API is open to everyone who can reach it");
+    } else if config.auth.users.is_empty() {
+        log::warn!("only tokens are configured, so browsers can't open the control page, which needs a user");
+    }
+    let layout = Layout::new(args.strands.clone());
+    let presets = PresetStore::open(args.presets_file.as_ref().map(PathBuf::from))?;
+    let location = match (args.latitude, args.longitude) {
+        (Some(latitude), Some(longitude)) => Some(Location { latitude: latitude, longitude: longitude }),
+        _ => None,
+    };
+    let schedule = ScheduleStore::open(args.schedule_file.as_ref().map(PathBuf::from), location)?;
+    let server_state = Arc::new(Mutex::new(ServerState::new(Path::new(&args.daemon_socket), args.strands.clone(), presets, schedule)?));
+    let monitor = server_state.lock().unwrap().firelight.monitor();
+    let external = server_state.lock().unwrap().firelight.external_input();
+    spawn_dmx_receivers(&args, &layout, &external)?;
+    let wled_udp_port = spawn_wled_receiver(&args, &external)?;
+    spawn_opc_server(&args, &layout, &external)?;
+    audio::spawn(&args.audio, server_state.lock().unwrap().firelight.audio_input())?;
+    let started = Instant::now();
+
+    let scheduler_state = server_state.clone();
+    std::thread::spawn(move || scheduler_thread(scheduler_state));
+    shutdown_on_signals(server_state.clone())?;
+
+    let alive_state = server_state.clone();
+    systemd::spawn_watchdog(move || {
+        // Blocks if a request handler got stuck with the lock.
+        drop(alive_state.lock());
+    });
+
+    let api_document = openapi::document(
+        "firelight-rest",
+        firelight_version,
+        &api_operations(&config.auth),
+        auth::is_enabled(&config.auth),
+    );
 
-    rouille::start_server(args.bind, move |request| {
-        rouille::log(&request, io::stdout(), || {
+    let handler = move |request: &rouille::Request| {
+        log_request(request, || {
+            if let Some(response) = check_auth(&config.auth, request) {
+                return response;
+            }
+            // The router doesn't support dots in paths.
+            if request.method() == "GET" && request.url() == "/openapi.json" {
+                return rouille::Response::json(&api_document);
+            }
+            if request.method() == "GET" && request.url() == "/presets.json" {
+                let state = match server_state.lock() {
+                    Ok(state) => state,
+                    Err(e) => return poisoned(e),
+                };
+                return rouille::Response::json(&wled::presets_json(state.presets.list()));
+            }
+            let wled_info = |state: &ServerState| wled::Info::new(
+                &args.instance_name,
+                firelight_version,
+                &state.firelight.status(),
+                &monitor.latest().colors,
+                wled_udp_port,
+                started.elapsed(),
+            );
             router!(request,
                 (GET) (/) => {
-                    // TODO: Make a cool HTML control page.
-                    return rouille::Response::redirect_302("/status");
+                    return rouille::Response::html(INDEX_HTML);
                 },
 
                 (GET) (/status) => {
-                    let state = try_or_400!(server_state.lock());
-                    return rouille::Response::json(&StatusResponse::from_control(state.last_state));
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&StatusResponse::from_state(&state));
                 },
 
                 (GET) (/about) => {
@@ -82,54 +1077,564 @@ fn main() -> anyhow::Result<()> {
                     return rouille::Response::json(&about);
                 },
 
+                (GET) (/metrics) => {
+                    let status = match server_state.lock() {
+                        Ok(state) => state.firelight.status(),
+                        Err(e) => return poisoned(e),
+                    };
+                    // Without holding the lock, since this waits for the daemon.
+                    let daemon_stats = daemon::query_stats(Path::new(&args.daemon_socket));
+                    let text = metrics_text(&status, &monitor.latest().colors, daemon_stats);
+                    return rouille::Response::from_data("text/plain; version=0.0.4", text);
+                },
+
+                (GET) (/json) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&wled::Full {
+                        state: wled_state(&state),
+                        info: wled_info(&state),
+                        effects: wled::effect_names(),
+                        palettes: wled::palette_names(),
+                    });
+                },
+
+                (GET) (/json/state) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&wled_state(&state));
+                },
+
+                (GET) (/json/info) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&wled_info(&state));
+                },
+
+                (GET) (/json/eff) => {
+                    return rouille::Response::json(&wled::effect_names());
+                },
+
+                (GET) (/json/pal) => {
+                    return rouille::Response::json(&wled::palette_names());
+                },
+
+                // WLED accepts state changes on both paths.
+                (POST) (/json/state) => {
+                    return handle_wled_update(&server_state, request);
+                },
+
+                (POST) (/json) => {
+                    return handle_wled_update(&server_state, request);
+                },
+
+                (GET) (/frame) => {
+                    let frame = monitor.latest();
+                    match request.get_param("format").as_deref() {
+                        None | Some("json") => {
+                            return rouille::Response::json(&FrameJson::new(frame, &layout));
+                        },
+                        Some("png") => {
+                            if layout.is_empty() {
+                                return ErrorResponse::new("there are no LEDs to draw").response(409);
+                            }
+                            let scale = request.get_param("scale")
+                                .and_then(|s| s.parse::<usize>().ok())
+                                .unwrap_or(8)
+                                .clamp(1, 64);
+                            return match firelight::preview::to_png(&layout, &frame.colors, scale) {
+                                Ok(png) => rouille::Response::from_data("image/png", png),
+                                Err(e) => ErrorResponse::new(e).response(500),
+                            };
+                        },
+                        Some(_) => {
+                            return ErrorResponse {
+                                valid_values: vec!["json".to_string(), "png".to_string()],
+                                ..ErrorResponse::field("format", "must be 'json' or 'png'")
+                            }.response(400);
+                        },
+                    }
+                },
+
+                (GET) (/frame/stream) => {
+                    let fps = request.get_param("fps")
+                        .and_then(|s| s.parse::<u32>().ok())
+                        .unwrap_or(10);
+                    let stream = FrameStream::new(monitor.clone(), layout.clone(), fps);
+                    // Without a length, the body is sent with `stream_response()`.
+                    return rouille::Response {
+                        status_code: 200,
+                        headers: vec![
+                            ("Content-Type".into(), "text/event-stream".into()),
+                            ("Cache-Control".into(), "no-cache".into()),
+                        ],
+                        data: rouille::ResponseBody::from_reader(stream),
+                        upgrade: None,
+                    };
+                },
+
                 (POST) (/control) => {
                     let maybe_input = post_input!(request, {
                         on: String,
-                        brightness: Option<u8>,
-                        color_hs: Vec<f32>,  // h in [0.0,360.0], s in [0.0, 100.0]
+                        brightness: Option<String>,
+                        color_hs: Vec<String>,
                         effect: Option<String>,
+                        speed: Option<String>,
                     });
                     let input = match maybe_input {
-                        Ok(v) => v,
-                        Err(e) => {println!("error {:?}", e); return rouille::Response::empty_400(); }
-                    };
-                    println!("got '/control' input {:?}", input);
-                    {
-                        let mut state = try_or_400!(server_state.lock());
-                        let mut control = state.last_state;
-                        if input.on == "True" {
-                            control.on = true;
-                        } else if input.on == "False" {
-                            control.on = false;
-                        } else {
-                            return rouille::Response::text("invalid value for 'on'").with_status_code(400);
-                        }
-                        if let Some(brightness) = input.brightness {
-                            control.brightness = brightness;
-                        }
-                        if let Some(effect) = input.effect {
-                            println!("got effect {}", effect);
-                            let maybe_effect = firelight::Effect::from_string(&effect);
-                            match maybe_effect {
-                                Ok(effect) => control.effect = effect,
-                                Err(_) => return rouille::Response::empty_400(),
-                            }
-                        }
-                        if !input.color_hs.is_empty() {
-                            if input.color_hs.len() != 2 {
-                                return rouille::Response::empty_400();
-                            }
-                            control.color_hs = (input.color_hs[0], input.color_hs[1]);
+                        Ok(input) => ControlForm {
+                            on: input.on,
+                            brightness: input.brightness,
+                            color_hs: input.color_hs,
+                            effect: input.effect,
+                            speed: input.speed,
+                        },
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    log::debug!(client = request.remote_addr().to_string(); "got '/control' input {:?}", input);
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    let (control, preset) = match input.apply(state.firelight.state(), &state.presets) {
+                        Ok(result) => result,
+                        Err(e) => return e.response(400),
+                    };
+                    let result = state.control(control);
+                    if let Some(preset) = preset {
+                        if preset.control == control {
+                            state.active_preset = Some(preset.name);
                         }
-                        state.last_state = control;
-                        state.firelight.control(control);
+                    }
+                    if let Err(e) = result {
+                        return unavailable(e);
                     }
                     return rouille::Response::text("success");
                 },
 
+                (GET) (/toggle) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&PowerResponse { on: state.firelight.is_on() });
+                },
+
+                (POST) (/toggle) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    state.active_preset = None;
+                    if let Err(e) = state.firelight.toggle() {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&PowerResponse { on: state.firelight.is_on() });
+                },
+
+                (GET) (/brightness) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&BrightnessBody { brightness: state.firelight.brightness() });
+                },
+
+                (PUT) (/brightness) => {
+                    let input: BrightnessBody = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    state.active_preset = None;
+                    if let Err(e) = state.firelight.set_brightness(input.brightness) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&input);
+                },
+
+                (POST) (/brightness/step) => {
+                    let input: BrightnessStepRequest = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    state.active_preset = None;
+                    if let Err(e) = state.firelight.adjust_brightness(input.delta) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&BrightnessBody { brightness: state.firelight.brightness() });
+                },
+
+                (GET) (/effect) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&EffectBody { effect: state.firelight.effect().to_string() });
+                },
+
+                // Like with `/control`, presets can be selected like an effect.
+                (PUT) (/effect) => {
+                    let input: EffectBody = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    let result = match state.presets.get(&input.effect).cloned() {
+                        Some(preset) => state.apply_preset(&preset),
+                        None => {
+                            let effect = match firelight::Effect::from_string(&input.effect) {
+                                Ok(effect) => effect,
+                                Err(_) => return invalid_effect(&input.effect, &state.presets).response(400),
+                            };
+                            state.active_preset = None;
+                            state.firelight.set_effect(effect)
+                        },
+                    };
+                    if let Err(e) = result {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&input);
+                },
+
+                (GET) (/color) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&ColorBody { color_hs: state.firelight.color_hs() });
+                },
+
+                (PUT) (/color) => {
+                    let input: ColorBody = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    if let Err(e) = check_color_hs(input.color_hs) {
+                        return e.response(400);
+                    }
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    state.active_preset = None;
+                    if let Err(e) = state.firelight.set_color_hs(input.color_hs) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&input);
+                },
+
+                (POST) (/sleep) => {
+                    let input: SleepRequest = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let duration = match minutes(input.minutes) {
+                        Ok(duration) => duration,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.firelight.sleep_timer(duration) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::text("success");
+                },
+
+                (DELETE) (/sleep) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.firelight.cancel_sleep_timer() {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::empty_204();
+                },
+
+                (PUT) (/wakeup) => {
+                    let input: WakeUpRequest = match json_body(request) {
+                        Ok(input) => input,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let wake_up = parse_wake_up_time(&input.at, Local::now())
+                        .and_then(|at| Ok(WakeUp { at: at, duration: minutes(input.minutes)? }));
+                    let wake_up = match wake_up {
+                        Ok(wake_up) => wake_up,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.firelight.wake_up(wake_up) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::json(&WakeUpResponse {
+                        at: to_rfc3339(wake_up.at),
+                        minutes: input.minutes,
+                    });
+                },
+
+                (DELETE) (/wakeup) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.firelight.cancel_wake_up() {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::empty_204();
+                },
+
+                (GET) (/presets) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return rouille::Response::json(&state.presets.list());
+                },
+
+                (GET) (/presets/{name: String}) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return match state.presets.get(&name) {
+                        Some(preset) => rouille::Response::json(preset),
+                        None => rouille::Response::empty_404(),
+                    };
+                },
+
+                // Stores the given state as a preset.
+                (PUT) (/presets/{name: String}) => {
+                    let control: Control = match json_body(request) {
+                        Ok(control) => control,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let preset = Preset { id: 0, name: name, control: control };
+                    if let Err(e) = preset.validate() {
+                        return ErrorResponse::new(e).response(400);
+                    }
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return match state.presets.insert(preset) {
+                        Ok(preset) => rouille::Response::json(&preset),
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
+                // Stores the current state as a preset.
+                (POST) (/presets/{name: String}) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    let preset = Preset { id: 0, name: name, control: state.firelight.state() };
+                    if let Err(e) = preset.validate() {
+                        return ErrorResponse::new(e).response(400);
+                    }
+                    return match state.presets.insert(preset) {
+                        Ok(preset) => {
+                            state.active_preset = Some(preset.name.clone());
+                            rouille::Response::json(&preset)
+                        },
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
+                (POST) (/presets/{name: String}/apply) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    let preset = match state.presets.get(&name) {
+                        Some(preset) => preset.clone(),
+                        None => return rouille::Response::empty_404(),
+                    };
+                    if let Err(e) = state.apply_preset(&preset) {
+                        return unavailable(e);
+                    }
+                    return rouille::Response::text("success");
+                },
+
+                (DELETE) (/presets/{name: String}) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return match state.presets.remove(&name) {
+                        Ok(true) => {
+                            if state.active_preset.as_ref() == Some(&name) {
+                                state.active_preset = None;
+                            }
+                            rouille::Response::empty_204()
+                        },
+                        Ok(false) => rouille::Response::empty_404(),
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
+                (GET) (/schedule) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    let rules: Vec<RuleResponse> = state.schedule.list()
+                        .iter()
+                        .map(|rule| RuleResponse::new(rule, &state.schedule))
+                        .collect();
+                    return rouille::Response::json(&rules);
+                },
+
+                (POST) (/schedule) => {
+                    let rule: Rule = match json_body(request) {
+                        Ok(rule) => rule,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.check_rule(&rule) {
+                        return ErrorResponse::new(e).response(400);
+                    }
+                    return match state.schedule.add(rule) {
+                        Ok(rule) => rouille::Response::json(&RuleResponse::new(&rule, &state.schedule)),
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
+                (GET) (/schedule/{id: u32}) => {
+                    let state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return match state.schedule.get(id) {
+                        Some(rule) => rouille::Response::json(&RuleResponse::new(rule, &state.schedule)),
+                        None => rouille::Response::empty_404(),
+                    };
+                },
+
+                (PUT) (/schedule/{id: u32}) => {
+                    let rule: Rule = match json_body(request) {
+                        Ok(rule) => rule,
+                        Err(e) => return ErrorResponse::new(e).response(400),
+                    };
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    if let Err(e) = state.check_rule(&rule) {
+                        return ErrorResponse::new(e).response(400);
+                    }
+                    return match state.schedule.replace(id, rule) {
+                        Ok(Some(rule)) => rouille::Response::json(&RuleResponse::new(&rule, &state.schedule)),
+                        Ok(None) => rouille::Response::empty_404(),
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
+                (DELETE) (/schedule/{id: u32}) => {
+                    let mut state = match server_state.lock() {
+                        Ok(state) => state,
+                        Err(e) => return poisoned(e),
+                    };
+                    return match state.schedule.remove(id) {
+                        Ok(true) => rouille::Response::empty_204(),
+                        Ok(false) => rouille::Response::empty_404(),
+                        Err(e) => ErrorResponse::new(e).response(500),
+                    };
+                },
+
                 _ => rouille::Response::empty_404()
             )
         })
-    });
-    return Ok(());
+    };
+
+    let listener = match systemd::tcp_listener()? {
+        Some(listener) => {
+            log::info!("listening on the socket passed by systemd");
+            listener
+        },
+        None => {
+            log::info!("starting server listening on {}", args.bind);
+            TcpListener::bind(&args.bind).map_err(|e| anyhow!("couldn't listen on {}: {}", args.bind, e))?
+        },
+    };
+    systemd::notify_ready();
+    return serve(listener, handler, tls);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Method and path of every route in the source of `main`, with path
+    /// parameters like `{name: String}` written as `{name}`.
+    fn routes() -> BTreeSet<(String, String)> {
+        let source = include_str!("firelight-rest.rs");
+        let source = &source[..source.find("#[cfg(test)]").unwrap()];
+        let mut routes = BTreeSet::new();
+        for line in source.lines().map(str::trim) {
+            // Routes of the router, like `(GET) (/presets/{name: String}) => {`.
+            if let Some(route) = line.strip_prefix('(').and_then(|line| line.strip_suffix(") => {")) {
+                if let Some((method, path)) = route.split_once(") (") {
+                    let path = path.split(": String").collect::<String>().split(": u32").collect();
+                    routes.insert((method.to_string(), path));
+                }
+            }
+            // Routes in front of the router, like `request.url() == "/openapi.json"`.
+            if let Some(rest) = line.strip_prefix("if request.method() == \"") {
+                let (method, rest) = rest.split_once('"').unwrap();
+                let path = rest.split('"').nth(1).unwrap();
+                routes.insert((method.to_string(), path.to_string()));
+            }
+        }
+        return routes;
+    }
+
+    #[test]
+    fn openapi_describes_all_routes() {
+        let document = openapi::document("test", "0", &api_operations(&AuthConfig::default()), false);
+        let mut described = BTreeSet::new();
+        for (path, operations) in document["paths"].as_object().unwrap() {
+            for method in operations.as_object().unwrap().keys() {
+                described.insert((method.to_uppercase(), path.clone()));
+            }
+        }
+        let routes = routes();
+        assert!(routes.len() > 30, "found only {} routes", routes.len());
+        assert_eq!(
+            routes.difference(&described).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "routes missing in the OpenAPI document"
+        );
+        assert_eq!(
+            described.difference(&routes).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "OpenAPI operations without a route"
+        );
+    }
 }
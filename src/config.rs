@@ -0,0 +1,286 @@
+// The config files of `firelight-daemon` and `firelight-rest`. The daemon
+// reads its file at startup and again whenever it receives SIGHUP, the
+// server only at startup.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use serde::Deserialize;
+
+use crate::args::DaemonArgs;
+use crate::args::ServerArgs;
+
+/// Contents of the config file. Everything is optional,
+/// since the command line can provide the same settings.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// The PWM channel to which the LED strip is connected.
+    pub channel: Option<usize>,
+
+    /// The DMA number used to drive the strip.
+    pub dma: Option<i32>,
+
+    /// The pin to which the LED strip is attached.
+    pub pin: Option<i32>,
+
+    /// How many LEDs the strip contains.
+    pub leds_count: Option<usize>,
+
+    pub watchdog: WatchdogConfig,
+
+    pub transform: TransformConfig,
+
+    pub socket: SocketConfig,
+}
+
+/// What the daemon shows when the renderer stops sending frames.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Fade the last frame to black.
+    Fade,
+    /// Show a static color.
+    Fallback,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds without a frame after which the watchdog takes over.
+    /// The watchdog is disabled if this isn't set.
+    pub timeout: Option<f32>,
+
+    pub action: WatchdogAction,
+
+    /// Color shown by the `fallback` action, as 0xRRGGBB.
+    pub fallback_color: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        return WatchdogConfig {
+            timeout: None,
+            action: WatchdogAction::Fade,
+            fallback_color: 0x331100,
+        };
+    }
+}
+
+/// Everything that is needed to initialize the LED strip.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StripConfig {
+    pub channel: usize,
+    pub dma: i32,
+    pub pin: i32,
+    pub leds_count: usize,
+}
+
+/// Corrections that the daemon applies to every frame, no matter
+/// which client sent it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Upper limit for all channels in [0, 255]. Colors are scaled
+    /// so that full brightness in a frame ends up at this value.
+    pub max_brightness: u8,
+
+    /// Factors in [0.0, 1.0] for the red, green and blue channel,
+    /// to calibrate the white point of the strip.
+    pub white_balance: [f32; 3],
+
+    /// Exponent of the gamma correction, where 1.0 disables it.
+    pub gamma: f32,
+
+    /// If not empty, the i-th color of a frame is shown on the LED at
+    /// position `index_map[i]` of the strip. Colors without an entry are
+    /// dropped and LEDs without a color stay dark, which allows to hide LEDs
+    /// or to reverse parts of the strip.
+    pub index_map: Vec<usize>,
+}
+
+impl Default for TransformConfig {
+    fn default() -> TransformConfig {
+        return TransformConfig {
+            max_brightness: 255,
+            white_balance: [1.0, 1.0, 1.0],
+            gamma: 1.0,
+            index_map: Vec::new(),
+        };
+    }
+}
+
+/// Who may connect to the daemon socket.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    /// Permissions of the socket file, e.g. `0o660`.
+    /// Sockets passed by systemd are left unchanged.
+    pub mode: u32,
+
+    /// Group of the socket file, as name or numeric id.
+    pub group: Option<String>,
+
+    /// If one of these lists isn't empty, only clients running as one of these
+    /// users or as a member of one of these groups are accepted. Root is always
+    /// accepted.
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> SocketConfig {
+        return SocketConfig {
+            mode: 0o660,
+            group: None,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+        };
+    }
+}
+
+impl SocketConfig {
+    /// Whether a client running as `uid` may connect. `groups` returns the
+    /// groups of the client and is only called if they need to be checked.
+    pub fn allows(&self, uid: u32, groups: impl FnOnce() -> Vec<u32>) -> bool {
+        if self.allowed_uids.is_empty() && self.allowed_gids.is_empty() {
+            return true;
+        }
+        if uid == 0 || self.allowed_uids.contains(&uid) {
+            return true;
+        }
+        return !self.allowed_gids.is_empty() && groups().iter().any(|gid| self.allowed_gids.contains(gid));
+    }
+}
+
+impl DaemonConfig {
+    pub fn load(path: &Path) -> anyhow::Result<DaemonConfig> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+        let config: DaemonConfig = toml::from_str(&text)
+            .map_err(|e| anyhow!("invalid config file {}: {}", path.display(), e))?;
+        if let Some(timeout) = config.watchdog.timeout {
+            // Also rejects values too large for a `Duration`, which would panic later.
+            if timeout <= 0.0 || Duration::try_from_secs_f32(timeout).is_err() {
+                bail!("invalid config file {}: 'watchdog.timeout' must be a positive number of seconds", path.display());
+            }
+        }
+        let transform = &config.transform;
+        if transform.white_balance.iter().any(|factor| !(0.0..=1.0).contains(factor)) {
+            bail!("invalid config file {}: 'transform.white_balance' must be in [0.0, 1.0]", path.display());
+        }
+        if !transform.gamma.is_finite() || transform.gamma <= 0.0 {
+            bail!("invalid config file {}: 'transform.gamma' must be positive", path.display());
+        }
+        if config.socket.mode > 0o777 {
+            bail!("invalid config file {}: 'socket.mode' must be at most 0o777", path.display());
+        }
+        return Ok(config);
+    }
+
+    /// Reads the config file given on the command line, if any.
+    pub fn from_args(args: &DaemonArgs) -> anyhow::Result<DaemonConfig> {
+        return match &args.config {
+            Some(path) => DaemonConfig::load(Path::new(path)),
+            None => Ok(DaemonConfig::default()),
+        };
+    }
+
+    /// Combines the strip settings of the file with those from
+    /// the command line, which take precedence.
+    pub fn strip(&self, args: &DaemonArgs) -> anyhow::Result<StripConfig> {
+        let missing = |name: &str| {
+            anyhow!("missing '{}', set it in the config file or with --{}", name, name.replace('_', "-"))
+        };
+        return Ok(StripConfig {
+            channel: args.channel.or(self.channel).ok_or_else(|| missing("channel"))?,
+            dma: args.dma.or(self.dma).ok_or_else(|| missing("dma"))?,
+            pin: args.pin.or(self.pin).ok_or_else(|| missing("pin"))?,
+            leds_count: args.leds_count.or(self.leds_count).ok_or_else(|| missing("leds_count"))?,
+        });
+    }
+}
+
+/// Contents of the config file of `firelight-rest`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub auth: AuthConfig,
+
+    /// Serve HTTPS instead of HTTP if set.
+    pub tls: Option<TlsConfig>,
+}
+
+/// What a client is allowed to do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only requests that don't change anything, i.e. `GET` and `HEAD`.
+    Read,
+    /// All requests.
+    Control,
+}
+
+/// Who may use the REST API. If neither tokens nor users are
+/// configured, authentication is disabled.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Allow `GET /about` and `GET /status` without credentials.
+    pub anonymous_status: bool,
+
+    /// Accepted in an `Authorization: Bearer <token>` header.
+    pub tokens: Vec<TokenConfig>,
+
+    /// Accepted with HTTP basic auth.
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    pub scope: Scope,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    pub scope: Scope,
+}
+
+/// Paths to PEM files with the certificate chain and the private key.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<ServerConfig> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+        let config: ServerConfig = toml::from_str(&text)
+            .map_err(|e| anyhow!("invalid config file {}: {}", path.display(), e))?;
+        if config.auth.tokens.iter().any(|token| token.token.is_empty()) {
+            bail!("invalid config file {}: 'auth.tokens' must not be empty strings", path.display());
+        }
+        if config.auth.users.iter().any(|user| user.name.is_empty() || user.name.contains(':')) {
+            bail!("invalid config file {}: user names must not be empty or contain ':'", path.display());
+        }
+        return Ok(config);
+    }
+
+    /// Reads the config file given on the command line, if any.
+    pub fn from_args(args: &ServerArgs) -> anyhow::Result<ServerConfig> {
+        return match &args.config {
+            Some(path) => ServerConfig::load(Path::new(path)),
+            None => Ok(ServerConfig::default()),
+        };
+    }
+}
@@ -1,6 +1,30 @@
+use anyhow::anyhow;
 use anyhow::bail;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// A daemon that stops reading must not stall the render thread forever.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sent on its own instead of a frame to ask the daemon for its `DaemonStats`.
+/// It can't be mistaken for a color, since colors leave the top byte empty.
+pub const STATS_QUERY: u32 = 0xff00_0001;
+
+// How long to wait for the answer to a stats query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub fn as_bytes(v: &mut [u32]) -> &mut [u8] {
     unsafe {
@@ -17,3 +41,190 @@ pub fn read_input(mut stream: &UnixStream, buffer: &mut [u32]) -> anyhow::Result
     }
     return Ok(n/4);
 }
+
+pub fn is_stats_query(message: &[u32]) -> bool {
+    return message == [STATS_QUERY];
+}
+
+/// What the daemon did since it started.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DaemonStats {
+    /// Frames received from clients and written to the strip.
+    pub frames_applied: u64,
+
+    /// Frames received while the strip wasn't initialized.
+    pub frames_dropped: u64,
+
+    /// Time spent writing frames to the strip, in seconds.
+    pub render_seconds_total: f64,
+    pub last_render_seconds: f64,
+    pub max_render_seconds: f64,
+}
+
+impl DaemonStats {
+    pub fn record_render(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        self.frames_applied += 1;
+        self.render_seconds_total += seconds;
+        self.last_render_seconds = seconds;
+        self.max_render_seconds = self.max_render_seconds.max(seconds);
+    }
+}
+
+/// Answers a stats query with a single line of JSON.
+pub fn write_stats(mut stream: &UnixStream, stats: &DaemonStats) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(stats)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    return Ok(());
+}
+
+/// Asks the daemon for its stats. This opens a separate connection,
+/// so that the answer can't get mixed up with frames.
+pub fn query_stats(path: &Path) -> anyhow::Result<DaemonStats> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| anyhow!("couldn't connect to {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+    stream.write_all(as_bytes(&mut [STATS_QUERY]))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let stats = serde_json::from_str(&line).map_err(|e| anyhow!("invalid stats from the daemon: {}", e))?;
+    return Ok(stats);
+}
+
+/// Identifies the process on the other end of a unix socket.
+#[derive(Copy, Clone, Debug)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Queries the credentials of the peer with `SO_PEERCRED`. These are
+/// the credentials at the time the peer called `connect`.
+pub fn peer_credentials(stream: &UnixStream) -> std::io::Result<PeerCredentials> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    });
+}
+
+/// The groups of user `uid` in the group database, including its primary
+/// group `gid`. `SO_PEERCRED` only reports the primary group of a peer.
+pub fn user_groups(uid: u32, gid: u32) -> std::io::Result<Vec<u32>> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut entry: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let error = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut entry) };
+        match error {
+            0 => break,
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            _ => return Err(std::io::Error::from_raw_os_error(error)),
+        }
+    }
+    // A uid without a user, e.g. from a container, has no other groups.
+    if entry.is_null() {
+        return Ok(vec![gid]);
+    }
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // Fails if the list is too short, and sets `count` to the length needed.
+        if unsafe { libc::getgrouplist(passwd.pw_name, gid, groups.as_mut_ptr(), &mut count) } >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+}
+
+/// A client connection to the daemon socket. If the daemon goes away,
+/// e.g. because it was restarted, the connection is re-established
+/// with exponential backoff.
+pub struct Connection {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    backoff: Duration,
+    next_attempt: Instant,
+    reconnects: u64,
+}
+
+impl Connection {
+    /// Connects to the daemon. Unlike later reconnects, this fails right away,
+    /// since a daemon that isn't running at all is usually a configuration error.
+    pub fn open(path: &Path) -> anyhow::Result<Connection> {
+        let stream = UnixStream::connect(path)
+            .map_err(|e| anyhow!("couldn't connect to {}: {}", path.display(), e))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        return Ok(Connection {
+            path: path.to_path_buf(),
+            stream: Some(stream),
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now(),
+            reconnects: 0,
+        });
+    }
+
+    /// How often the connection was re-established.
+    pub fn reconnects(&self) -> u64 {
+        return self.reconnects;
+    }
+
+    /// Sends a frame, reconnecting first if necessary. Returns `None` without
+    /// sending anything while waiting for the next attempt to reconnect.
+    pub fn send(&mut self, colors: &mut [u32]) -> Option<std::io::Result<()>> {
+        let now = Instant::now();
+        if self.stream.is_none() {
+            if now < self.next_attempt {
+                return None;
+            }
+            let connected = UnixStream::connect(&self.path)
+                .and_then(|stream| stream.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| stream));
+            match connected {
+                Ok(stream) => {
+                    log::debug!(socket = self.path.display().to_string(); "reconnected to the daemon");
+                    self.stream = Some(stream);
+                    self.reconnects += 1;
+                },
+                Err(e) => {
+                    self.back_off(now);
+                    return Some(Err(e));
+                },
+            }
+        }
+        let result = self.stream.as_mut()?.write_all(as_bytes(colors));
+        match &result {
+            Ok(()) => self.backoff = MIN_BACKOFF,
+            Err(_) => {
+                // A partially written frame can't be completed, so
+                // always start over with a new connection.
+                self.stream = None;
+                self.back_off(now);
+            },
+        }
+        return Some(result);
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.next_attempt = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,443 @@
+// Receives DMX universes over E1.31 (sACN) or Art-Net, as sent by lighting
+// consoles and sequencers like xLights, and shows them on the strip instead
+// of the rendered frames for as long as packets keep arriving.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::UdpSocket;
+
+use anyhow::anyhow;
+use anyhow::bail;
+
+use crate::layout::Layout;
+use crate::renderer::ExternalInput;
+use crate::renderer::EXTERNAL_TIMEOUT;
+
+const CHANNELS_PER_UNIVERSE: usize = 512;
+const CHANNELS_PER_LED: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    E131,
+    ArtNet,
+}
+
+impl Protocol {
+    fn name(&self) -> &'static str {
+        return match self {
+            Protocol::E131 => "e1.31",
+            Protocol::ArtNet => "art-net",
+        };
+    }
+
+    /// The lowest universe of the protocol, which is used unless another one
+    /// is configured. E1.31 doesn't use universe 0 for data.
+    pub fn first_universe(&self) -> u16 {
+        return match self {
+            Protocol::E131 => 1,
+            Protocol::ArtNet => 0,
+        };
+    }
+
+    fn parse<'a>(&self, packet: &'a [u8]) -> Option<DmxPacket<'a>> {
+        return match self {
+            Protocol::E131 => parse_e131(packet),
+            Protocol::ArtNet => parse_artnet(packet),
+        };
+    }
+}
+
+/// The channel values for one universe, taken from a data packet.
+#[derive(Debug, PartialEq)]
+pub struct DmxPacket<'a> {
+    pub universe: u16,
+    /// Used to drop packets that arrive out of order. Art-Net senders may
+    /// not number their packets.
+    pub sequence: Option<u8>,
+    /// The sender stops sending this universe.
+    pub terminated: bool,
+    pub data: &'a [u8],
+}
+
+fn u16_be(packet: &[u8], offset: usize) -> u16 {
+    return u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+}
+
+fn u32_be(packet: &[u8], offset: usize) -> u32 {
+    return u32::from_be_bytes([packet[offset], packet[offset + 1], packet[offset + 2], packet[offset + 3]]);
+}
+
+/// Parses an E1.31 data packet, see ANSI E1.31-2018 section 4. Returns `None` for
+/// other packets, like synchronization or discovery, and for preview data.
+pub fn parse_e131(packet: &[u8]) -> Option<DmxPacket<'_>> {
+    const HEADER: usize = 126;
+    if packet.len() < HEADER || &packet[4..16] != b"ASC-E1.17\0\0\0" {
+        return None;
+    }
+    // Root, framing and DMP layer vectors of a data packet.
+    if u32_be(packet, 18) != 0x4 || u32_be(packet, 40) != 0x2 || packet[117] != 0x2 {
+        return None;
+    }
+    let options = packet[112];
+    let preview = options & 0x80 != 0;
+    // Start codes other than 0 carry something else than levels.
+    if preview || packet[125] != 0 {
+        return None;
+    }
+    // The property values include the start code.
+    let channels = (u16_be(packet, 123) as usize).saturating_sub(1);
+    let end = (HEADER + channels).min(packet.len());
+    return Some(DmxPacket {
+        universe: u16_be(packet, 113),
+        sequence: Some(packet[111]),
+        terminated: options & 0x40 != 0,
+        data: &packet[HEADER..end],
+    });
+}
+
+/// Parses an ArtDmx packet, see the Art-Net 4 specification. Returns `None`
+/// for other packets, like ArtPoll.
+pub fn parse_artnet(packet: &[u8]) -> Option<DmxPacket<'_>> {
+    const HEADER: usize = 18;
+    const OP_DMX: u16 = 0x5000;
+    if packet.len() < HEADER || &packet[0..8] != b"Art-Net\0" {
+        return None;
+    }
+    // The only little-endian field of the protocol.
+    if u16::from_le_bytes([packet[8], packet[9]]) != OP_DMX {
+        return None;
+    }
+    let channels = u16_be(packet, 16) as usize;
+    let end = (HEADER + channels).min(packet.len());
+    return Some(DmxPacket {
+        // Net and SubUni form the 15 bit port address.
+        universe: ((packet[15] as u16 & 0x7f) << 8) | packet[14] as u16,
+        sequence: if packet[12] == 0 { None } else { Some(packet[12]) },
+        terminated: false,
+        data: &packet[HEADER..end],
+    });
+}
+
+/// A run of consecutive LEDs whose channels are in the same universe.
+#[derive(Clone, Debug)]
+struct Chunk {
+    universe: u16,
+    // Index of the first channel within the universe, starting at 0.
+    channel: usize,
+    led: usize,
+    count: usize,
+}
+
+/// Where the channels of each LED are, three per LED in RGB order. An LED's
+/// channels are never split across universes, so a universe holds up to 170
+/// LEDs, like pixel controllers usually expect.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    chunks: Vec<Chunk>,
+}
+
+impl Mapping {
+    /// Maps the strip to the universes starting at `start_universe`, beginning
+    /// at channel `start_channel` (1-based) of the first one. With
+    /// `universe_per_strand`, every strand starts at the first channel of
+    /// a new universe, otherwise the LEDs are packed.
+    pub fn new(layout: &Layout, start_universe: u16, start_channel: u16, universe_per_strand: bool) -> anyhow::Result<Mapping> {
+        if start_channel == 0 || start_channel as usize > CHANNELS_PER_UNIVERSE {
+            bail!("the start channel must be in [1, {}]", CHANNELS_PER_UNIVERSE);
+        }
+        let segments = if universe_per_strand {
+            layout.strands().to_vec()
+        } else {
+            vec![layout.len()]
+        };
+        let overflow = || anyhow!("the strip doesn't fit into the universes after {}", start_universe);
+        let mut chunks = Vec::new();
+        let mut universe = start_universe;
+        let mut channel = start_channel as usize - 1;
+        let mut led = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 && channel > 0 {
+                universe = universe.checked_add(1).ok_or_else(overflow)?;
+                channel = 0;
+            }
+            let mut remaining = *segment;
+            while remaining > 0 {
+                let capacity = (CHANNELS_PER_UNIVERSE - channel) / CHANNELS_PER_LED;
+                if capacity == 0 {
+                    universe = universe.checked_add(1).ok_or_else(overflow)?;
+                    channel = 0;
+                    continue;
+                }
+                let count = remaining.min(capacity);
+                chunks.push(Chunk { universe: universe, channel: channel, led: led, count: count });
+                led += count;
+                remaining -= count;
+                channel += count * CHANNELS_PER_LED;
+            }
+        }
+        return Ok(Mapping { chunks: chunks });
+    }
+
+    /// All universes that contain channels of the strip.
+    pub fn universes(&self) -> Vec<u16> {
+        let mut universes: Vec<u16> = self.chunks.iter().map(|chunk| chunk.universe).collect();
+        universes.dedup();
+        return universes;
+    }
+
+    /// Writes the LEDs that are in the given universe to the external frame.
+    fn apply(&self, universe: u16, data: &[u8], source: &str, input: &ExternalInput) {
+        for chunk in self.chunks.iter().filter(|chunk| chunk.universe == universe) {
+            let end = (chunk.channel + chunk.count * CHANNELS_PER_LED).min(data.len());
+            if chunk.channel >= end {
+                continue;
+            }
+            let colors: Vec<u32> = data[chunk.channel..end]
+                .chunks_exact(CHANNELS_PER_LED)
+                .map(|rgb| ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | rgb[2] as u32)
+                .collect();
+            input.update(source, chunk.led, &colors, EXTERNAL_TIMEOUT);
+        }
+    }
+}
+
+/// Whether a packet with sequence number `sequence` is older than the last one,
+/// using the rule from E1.31 section 6.7.2.
+fn out_of_order(last: u8, sequence: u8) -> bool {
+    let difference = sequence.wrapping_sub(last) as i8;
+    return difference <= 0 && difference > -20;
+}
+
+/// The multicast group to which E1.31 sources send a universe.
+fn e131_group(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    return Ipv4Addr::new(239, 255, high, low);
+}
+
+/// Receives packets on `socket` until an error occurs, and writes the
+/// universes covered by `mapping` to `input`. For E1.31, this also joins
+/// the multicast groups of these universes.
+pub fn receive(socket: UdpSocket, protocol: Protocol, mapping: Mapping, input: ExternalInput) -> anyhow::Result<()> {
+    if protocol == Protocol::E131 && socket.local_addr()?.is_ipv4() {
+        for universe in mapping.universes() {
+            // Unicast still works without the group, e.g. in containers without a multicast route.
+            if let Err(e) = socket.join_multicast_v4(&e131_group(universe), &Ipv4Addr::UNSPECIFIED) {
+                log::warn!(universe = universe; "couldn't join the multicast group of the universe: {}", e);
+            }
+        }
+    }
+    log::info!("receiving {} universes {:?} on {}", protocol.name(), mapping.universes(), socket.local_addr()?);
+    let mut buffer = [0u8; 1500];
+    let mut sequences: HashMap<(IpAddr, u16), u8> = HashMap::new();
+    loop {
+        let (len, sender) = socket.recv_from(&mut buffer)?;
+        let packet = match protocol.parse(&buffer[..len]) {
+            Some(packet) => packet,
+            None => continue,
+        };
+        let source = format!("{} from {}", protocol.name(), sender.ip());
+        if packet.terminated {
+            input.release(&source);
+            continue;
+        }
+        if let Some(sequence) = packet.sequence {
+            let key = (sender.ip(), packet.universe);
+            if let Some(last) = sequences.insert(key, sequence) {
+                if out_of_order(last, sequence) {
+                    sequences.insert(key, last);
+                    continue;
+                }
+            }
+        }
+        mapping.apply(packet.universe, packet.data, &source, &input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an E1.31 data packet the way sACN senders lay it out, with
+    /// the lengths of all three layers filled in.
+    fn e131(universe: u16, sequence: u8, options: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 126];
+        let len = (packet.len() + data.len()) as u16;
+        packet[0..2].copy_from_slice(&0x0010u16.to_be_bytes());
+        packet[4..16].copy_from_slice(b"ASC-E1.17\0\0\0");
+        packet[16..18].copy_from_slice(&(0x7000 | (len - 16)).to_be_bytes());
+        packet[18..22].copy_from_slice(&0x4u32.to_be_bytes());
+        packet[22..38].copy_from_slice(&[0x5c; 16]);
+        packet[38..40].copy_from_slice(&(0x7000 | (len - 38)).to_be_bytes());
+        packet[40..44].copy_from_slice(&0x2u32.to_be_bytes());
+        packet[44..51].copy_from_slice(b"xLights");
+        packet[108] = 100;
+        packet[111] = sequence;
+        packet[112] = options;
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[115..117].copy_from_slice(&(0x7000 | (len - 115)).to_be_bytes());
+        packet[117] = 0x2;
+        packet[118] = 0xa1;
+        packet[121..123].copy_from_slice(&1u16.to_be_bytes());
+        packet[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+        packet.extend_from_slice(data);
+        return packet;
+    }
+
+    /// Builds an ArtDmx packet from its SubUni and Net fields.
+    fn artnet(sub_uni: u8, net: u8, sequence: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = b"Art-Net\0".to_vec();
+        packet.extend_from_slice(&[0x00, 0x50, 0x00, 0x0e, sequence, 0x00, sub_uni, net]);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        return packet;
+    }
+
+    const LEVELS: [u8; 6] = [255, 0, 0, 0, 128, 255];
+
+    #[test]
+    fn e131_data_packets() {
+        let packet = e131(1, 7, 0, &LEVELS);
+        let expected = DmxPacket { universe: 1, sequence: Some(7), terminated: false, data: &LEVELS };
+        assert_eq!(parse_e131(&packet), Some(expected));
+
+        let packet = e131(63999, 0, 0x40, &LEVELS);
+        let parsed = parse_e131(&packet).unwrap();
+        assert_eq!((parsed.universe, parsed.terminated), (63999, true));
+    }
+
+    #[test]
+    fn e131_packets_without_levels_are_ignored() {
+        let preview = e131(1, 0, 0x80, &LEVELS);
+        let mut start_code = e131(1, 0, 0, &LEVELS);
+        start_code[125] = 0xdd;
+        let mut synchronization = e131(1, 0, 0, &[]);
+        synchronization[40..44].copy_from_slice(&0x1u32.to_be_bytes());
+        let mut identifier = e131(1, 0, 0, &LEVELS);
+        identifier[4..16].copy_from_slice(b"ASC-E1.15\0\0\0");
+        let truncated = e131(1, 0, 0, &[])[..125].to_vec();
+        for packet in [preview, start_code, synchronization, identifier, truncated] {
+            assert_eq!(parse_e131(&packet), None);
+        }
+    }
+
+    #[test]
+    fn e131_data_is_limited_to_the_packet() {
+        let mut packet = e131(1, 0, 0, &LEVELS);
+        packet[123..125].copy_from_slice(&513u16.to_be_bytes());
+        assert_eq!(parse_e131(&packet).unwrap().data, &LEVELS);
+        packet[123..125].copy_from_slice(&3u16.to_be_bytes());
+        assert_eq!(parse_e131(&packet).unwrap().data, &LEVELS[..2]);
+        packet[123..125].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(parse_e131(&packet).unwrap().data, &[] as &[u8]);
+    }
+
+    #[test]
+    fn artnet_port_addresses() {
+        // (SubUni, Net, universe)
+        let cases = [(0x00, 0x00, 0), (0x01, 0x00, 1), (0x23, 0x01, 0x123), (0xff, 0x7f, 0x7fff), (0x05, 0xff, 0x7f05)];
+        for (sub_uni, net, universe) in cases {
+            let packet = artnet(sub_uni, net, 1, &LEVELS);
+            assert_eq!(parse_artnet(&packet).unwrap().universe, universe, "SubUni {:#x}, Net {:#x}", sub_uni, net);
+        }
+    }
+
+    #[test]
+    fn artnet_data_packets() {
+        let packet = artnet(0, 0, 42, &LEVELS);
+        let expected = DmxPacket { universe: 0, sequence: Some(42), terminated: false, data: &LEVELS };
+        assert_eq!(parse_artnet(&packet), Some(expected));
+        // A sequence of 0 means that the sender doesn't number its packets.
+        assert_eq!(parse_artnet(&artnet(0, 0, 0, &LEVELS)).unwrap().sequence, None);
+
+        let mut long = artnet(0, 0, 1, &LEVELS);
+        long[16..18].copy_from_slice(&512u16.to_be_bytes());
+        assert_eq!(parse_artnet(&long).unwrap().data, &LEVELS);
+    }
+
+    #[test]
+    fn artnet_packets_without_levels_are_ignored() {
+        let mut poll = artnet(0, 0, 0, &[]);
+        poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        let mut big_endian = artnet(0, 0, 0, &LEVELS);
+        big_endian[8..10].copy_from_slice(&0x5000u16.to_be_bytes());
+        let truncated = artnet(0, 0, 0, &[])[..17].to_vec();
+        for packet in [poll, big_endian, truncated, e131(1, 0, 0, &LEVELS)] {
+            assert_eq!(parse_artnet(&packet), None);
+        }
+    }
+
+    /// A chunk as (universe, channel, led, count).
+    type ChunkTuple = (u16, usize, usize, usize);
+
+    /// Strands, start universe, start channel, universe per strand and the expected chunks.
+    type MappingCase = (&'static [usize], u16, u16, bool, &'static [ChunkTuple]);
+
+    fn chunks(strands: &[usize], universe: u16, channel: u16, universe_per_strand: bool) -> anyhow::Result<Vec<ChunkTuple>> {
+        let mapping = Mapping::new(&Layout::new(strands.to_vec()), universe, channel, universe_per_strand)?;
+        return Ok(mapping.chunks.iter().map(|c| (c.universe, c.channel, c.led, c.count)).collect());
+    }
+
+    #[test]
+    fn mappings() {
+        let cases: &[MappingCase] = &[
+            (&[10], 1, 1, false, &[(1, 0, 0, 10)]),
+            (&[10], 0, 1, false, &[(0, 0, 0, 10)]),
+            // 170 LEDs use 510 channels, the last two stay unused.
+            (&[200], 1, 1, false, &[(1, 0, 0, 170), (2, 0, 170, 30)]),
+            (&[10], 1, 4, false, &[(1, 3, 0, 10)]),
+            // An LED's channels aren't split across universes.
+            (&[10], 1, 508, false, &[(1, 507, 0, 1), (2, 0, 1, 9)]),
+            (&[10], 1, 511, false, &[(2, 0, 0, 10)]),
+            (&[10, 10], 1, 1, false, &[(1, 0, 0, 20)]),
+            (&[10, 10], 1, 1, true, &[(1, 0, 0, 10), (2, 0, 10, 10)]),
+            (&[10, 10], 1, 4, true, &[(1, 3, 0, 10), (2, 0, 10, 10)]),
+            (&[170, 10], 1, 1, true, &[(1, 0, 0, 170), (2, 0, 170, 10)]),
+            (&[200, 10], 1, 1, true, &[(1, 0, 0, 170), (2, 0, 170, 30), (3, 0, 200, 10)]),
+            (&[10], 65535, 1, false, &[(65535, 0, 0, 10)]),
+        ];
+        for (strands, universe, channel, per_strand, expected) in cases {
+            let actual = chunks(strands, *universe, *channel, *per_strand).unwrap();
+            assert_eq!(actual, *expected, "strands {:?} from {}/{}, per strand: {}", strands, universe, channel, per_strand);
+        }
+    }
+
+    #[test]
+    fn invalid_mappings() {
+        let cases: &[(&[usize], u16, u16, bool)] = &[
+            (&[10], 1, 0, false),
+            (&[10], 1, 513, false),
+            (&[200], 65535, 1, false),
+            (&[10], 65535, 511, false),
+            (&[10, 10], 65535, 1, true),
+        ];
+        for (strands, universe, channel, per_strand) in cases {
+            assert!(chunks(strands, *universe, *channel, *per_strand).is_err(), "strands {:?} from {}/{}", strands, universe, channel);
+        }
+    }
+
+    #[test]
+    fn universes() {
+        let mapping = Mapping::new(&Layout::new(vec![100, 100, 300]), 0, 1, true).unwrap();
+        assert_eq!(mapping.universes(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        // (last, sequence, out of order)
+        let cases = [
+            (5, 6, false),
+            (5, 5, true),
+            (5, 4, true),
+            (5, 242, true),
+            // A jump back of 20 or more means that the sender restarted.
+            (5, 241, false),
+            (255, 0, false),
+            (0, 255, true),
+            (5, 133, false),
+        ];
+        for (last, sequence, expected) in cases {
+            assert_eq!(out_of_order(last, sequence), expected, "{} after {}", sequence, last);
+        }
+    }
+}
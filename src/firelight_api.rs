@@ -1,24 +1,53 @@
 // FIXME: move everything else into separate files
 
+use std::ops::RangeInclusive;
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use anyhow::anyhow;
+use anyhow::bail;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use crate::audio::AudioInput;
+use crate::daemon;
+use crate::presets::Preset;
 use crate::renderer;
-use std::os::unix::net::UnixStream;
+use std::path::Path;
 
+use crate::renderer::ExternalInput;
+use crate::renderer::FrameMonitor;
 use crate::renderer::RendererCommand;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum Effect {
     Static,
     Fire,
+    /// A fire whose height follows the loudness of the audio input.
+    AudioFire,
+    /// A level meter for the loudness of the audio input on every strand.
+    Vu,
+    /// The spectrum of the audio input, spread over the strands.
+    Spectrum,
 }
 
 impl Effect {
+    /// All effects supported by the renderer.
+    pub fn all() -> &'static [Effect] {
+        return &[Effect::Static, Effect::Fire, Effect::AudioFire, Effect::Vu, Effect::Spectrum];
+    }
+
     pub fn to_string(&self) -> String {
         return match self {
             Effect::Static => "static".to_string(),
             Effect::Fire => "fire".to_string(),
+            Effect::AudioFire => "audiofire".to_string(),
+            Effect::Vu => "vu".to_string(),
+            Effect::Spectrum => "spectrum".to_string(),
         };
     }
 
@@ -26,6 +55,9 @@ impl Effect {
         return match s {
             "static" => Ok(Effect::Static),
             "fire" => Ok(Effect::Fire),
+            "audiofire" => Ok(Effect::AudioFire),
+            "vu" => Ok(Effect::Vu),
+            "spectrum" => Ok(Effect::Spectrum),
             _ => Err(anyhow!("invalid effect {}", s)),
         }
     }
@@ -33,7 +65,7 @@ impl Effect {
 
 /// Sent by clients.
 /// Used to control the state of the renderer.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Control {
     /// Whether the lamp is currently on or off.
     pub on: bool,
@@ -46,6 +78,10 @@ pub struct Control {
 
     /// Hue in [0.0, 360.0], Saturation in [0.0, 100.0]
     pub color_hs: (f32, f32),
+
+    /// Animation speed of dynamic effects in [0.0, 10.0],
+    /// where 1.0 is the normal speed. Ignored by `static`.
+    pub speed: f32,
 }
 
 impl Control {
@@ -56,104 +92,337 @@ impl Control {
             effect: Effect::Static,
             brightness: 255,
             color_hs: (0.0, 0.0),
+            speed: 1.0,
         };
     }
+
+    /// Checks that all values are in their documented ranges. The API
+    /// checks this for requests, but a state can also come from a file.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_color_hs(self.color_hs)?;
+        if !SPEED_RANGE.contains(&self.speed) {
+            bail!("speed must be in [{}, {}]", SPEED_RANGE.start(), SPEED_RANGE.end());
+        }
+        return Ok(());
+    }
+}
+
+/// The documented range of `Control::speed`.
+pub const SPEED_RANGE: RangeInclusive<f32> = 0.0..=10.0;
+
+/// Checks that the hue is in [0, 360] and the saturation in [0, 100].
+pub fn validate_color_hs(color_hs: (f32, f32)) -> anyhow::Result<()> {
+    let (hue, saturation) = color_hs;
+    if !(0.0..=360.0).contains(&hue) {
+        bail!("hue must be in [0, 360]");
+    }
+    if !(0.0..=100.0).contains(&saturation) {
+        bail!("saturation must be in [0, 100]");
+    }
+    return Ok(());
+}
+
+/// A sunrise simulation, ramping up from a dim deep red
+/// to a bright warm white until the target time.
+#[derive(Copy, Clone, Debug)]
+pub struct WakeUp {
+    /// When the ramp ends.
+    pub at: SystemTime,
+
+    /// How long before `at` the ramp starts.
+    pub duration: Duration,
 }
 
+/// Whether the rendered frames actually reach the daemon.
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// False if the last write to the daemon socket failed.
+    pub daemon_connected: bool,
+
+    /// When the last frame was successfully sent to the daemon.
+    pub last_frame: Option<SystemTime>,
+
+    /// Number of failed attempts to send a frame or to reconnect.
+    pub write_errors: u64,
+
+    /// How often the connection to the daemon was re-established.
+    pub reconnects: u64,
+
+    /// The most recent write error.
+    pub last_error: Option<String>,
+}
+
+/// The error of the methods that change the state if the renderer accepted
+/// the change, but currently can't write to the daemon. Callers can tell it
+/// apart from other errors with `anyhow::Error::is`.
+#[derive(Clone, Debug)]
+pub struct DaemonUnavailable {
+    pub reason: String,
+}
+
+impl std::fmt::Display for DaemonUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "not connected to the daemon: {}", self.reason);
+    }
+}
+
+impl std::error::Error for DaemonUnavailable {}
+
+/// How fast the render thread produces frames.
+#[derive(Clone, Debug, Default)]
+pub struct RenderStats {
+    /// Number of frames output since the start, including external ones.
+    pub frames: u64,
+
+    /// Frames output per second, averaged over roughly the last second.
+    pub fps: f32,
+
+    /// Number of frames the renderer computed itself.
+    pub rendered: u64,
+
+    /// Time spent computing the rendered frames, without sending them.
+    pub render_time: Duration,
+}
+
+/// Reported back by the render thread.
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// The current state of the renderer. Usually this is the last
+    /// state that was sent, but timers can change it on their own.
+    pub control: Control,
+
+    /// When the running sleep timer will turn the lamp off.
+    pub sleep_until: Option<SystemTime>,
+
+    /// The pending or running wake-up light.
+    pub wake_up: Option<WakeUp>,
+
+    pub health: Health,
+
+    pub render: RenderStats,
+
+    /// The receiver whose frames are currently shown instead of rendered ones.
+    pub external_source: Option<String>,
+}
 
 pub struct Handle {
     thread: Option<std::thread::JoinHandle<()>>,
     tx: mpsc::Sender<RendererCommand>,
+    monitor: FrameMonitor,
+    external: ExternalInput,
+    audio: AudioInput,
 
-    // Remember the last-sent state so we can offer
+    // Shared with the render thread. We also update it
+    // ourselves when sending a new state, so we can offer
     // convenience methods to toggle on/off, adjust
-    // brightness, etc.
-    state: Control,
+    // brightness, etc. without waiting for the renderer.
+    status: Arc<Mutex<Status>>,
 }
 
 impl Handle {
-    pub fn new(socket: UnixStream, strands: Vec<usize>) -> Handle {
+    /// Connects to the daemon and starts the render thread. If the
+    /// daemon goes away later, the render thread keeps reconnecting.
+    pub fn new(socket_path: &Path, strands: Vec<usize>) -> anyhow::Result<Handle> {
+        let connection = daemon::Connection::open(socket_path)?;
         let (tx, rx) = mpsc::channel();
+        let monitor = FrameMonitor::default();
+        let thread_monitor = monitor.clone();
+        let external = ExternalInput::new(strands.iter().sum());
+        let thread_external = external.clone();
+        let audio = AudioInput::default();
+        let thread_audio = audio.clone();
+        let status = Arc::new(Mutex::new(Status {
+            control: Control::default(),
+            sleep_until: None,
+            wake_up: None,
+            health: Health {
+                daemon_connected: true,
+                last_frame: None,
+                write_errors: 0,
+                reconnects: 0,
+                last_error: None,
+            },
+            render: RenderStats::default(),
+            external_source: None,
+        }));
+        let thread_status = status.clone();
         let join_handle = std::thread::spawn(move || {
             let thread_data = renderer::RenderThreadData {
                 rx: rx,
-                socket: socket,
+                connection: connection,
                 strands: strands,
                 state: Control::default(),
+                monitor: thread_monitor,
+                external: thread_external,
+                audio: thread_audio,
+                status: thread_status,
+                sleep_timer: None,
+                wake_up: None,
+                last_output: None,
             };
 
             return renderer::render_thread(thread_data);
         });
 
-        return Handle {
+        return Ok(Handle {
             thread: Some(join_handle),
             tx: tx,
-            state: Control::default(),
-        };
+            monitor: monitor,
+            external: external,
+            audio: audio,
+            status: status,
+        });
+    }
+
+    // All methods that change the state return an error if the command
+    // can't be delivered to the render thread, or a `DaemonUnavailable` if
+    // the renderer reported that it currently can't write to the daemon.
+    // In the latter case, the new state has still been accepted and will be
+    // displayed as soon as the renderer has reconnected.
+
+    fn send(&self, command: RendererCommand) -> anyhow::Result<()> {
+        if self.tx.send(command).is_err() {
+            bail!("the render thread is not running");
+        }
+        return self.check_health();
+    }
+
+    /// Returns a `DaemonUnavailable` if the renderer can't currently write to the daemon.
+    pub fn check_health(&self) -> anyhow::Result<()> {
+        let health = self.status().health;
+        if !health.daemon_connected {
+            return Err(DaemonUnavailable {
+                reason: health.last_error.unwrap_or_else(|| "unknown error".to_string()),
+            }.into());
+        }
+        return Ok(());
+    }
+
+    /// Fully set state. This cancels a running sleep timer or wake-up light.
+    pub fn control(&mut self, control: Control) -> anyhow::Result<()> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner()).control = control;
+        return self.send(RendererCommand::ControlMsg(control));
+    }
+
+    /// Gradually fade out over the given duration, then turn off.
+    pub fn sleep_timer(&mut self, duration: Duration) -> anyhow::Result<()> {
+        return self.send(RendererCommand::SleepTimer(Some(duration)));
+    }
+
+    pub fn cancel_sleep_timer(&mut self) -> anyhow::Result<()> {
+        return self.send(RendererCommand::SleepTimer(None));
+    }
+
+    /// Schedule a wake-up light, replacing any previous one.
+    pub fn wake_up(&mut self, wake_up: WakeUp) -> anyhow::Result<()> {
+        return self.send(RendererCommand::WakeUp(Some(wake_up)));
+    }
+
+    pub fn cancel_wake_up(&mut self) -> anyhow::Result<()> {
+        return self.send(RendererCommand::WakeUp(None));
     }
 
-    /// Fully set state.
-    pub fn control(&mut self, control: Control) {
-        self.state = control;
-        let _ = self.tx.send(RendererCommand::ControlMsg(control));
+    /// Set the state stored in a preset.
+    pub fn apply_preset(&mut self, preset: &Preset) -> anyhow::Result<()> {
+        return self.control(preset.control);
     }
 
     // Convenience functions to partially change the state.
 
     /// Toggle the lamp on/off.
-    pub fn toggle(&mut self) {
-        let mut toggled = self.state;
-        toggled.on = !self.state.on;
-        self.control(toggled);
+    pub fn toggle(&mut self) -> anyhow::Result<()> {
+        let mut toggled = self.state();
+        toggled.on = !toggled.on;
+        return self.control(toggled);
     }
 
-    pub fn set_brightness(&mut self, brightness: u8) {
-        let mut adjusted = self.state;
+    pub fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        let mut adjusted = self.state();
         adjusted.brightness = brightness;
-        self.control(adjusted);
+        return self.control(adjusted);
     }
 
-    pub fn adjust_brightness(&mut self, brightness_delta: i32) {
-        let brightness = if brightness_delta > 0 {
-            self.state.brightness.saturating_add(brightness_delta as u8)
-        } else {
-            self.state
-                .brightness
-                .saturating_sub(-brightness_delta as u8)
-        };
-        let mut adjusted = self.state;
-        adjusted.brightness = brightness;
-        self.control(adjusted);
+    /// Change the brightness by a delta, stopping at 0 and 255.
+    pub fn adjust_brightness(&mut self, brightness_delta: i32) -> anyhow::Result<()> {
+        let mut adjusted = self.state();
+        adjusted.brightness = (adjusted.brightness as i32)
+            .saturating_add(brightness_delta)
+            .clamp(0, 255) as u8;
+        return self.control(adjusted);
+    }
+
+    pub fn set_effect(&mut self, effect: Effect) -> anyhow::Result<()> {
+        let mut adjusted = self.state();
+        adjusted.effect = effect;
+        return self.control(adjusted);
+    }
+
+    pub fn set_color_hs(&mut self, color_hs: (f32, f32)) -> anyhow::Result<()> {
+        let mut adjusted = self.state();
+        adjusted.color_hs = color_hs;
+        return self.control(adjusted);
     }
 
     // Getters for the current state.
 
+    pub fn status(&self) -> Status {
+        return self.status.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    }
+
+    pub fn state(&self) -> Control {
+        return self.status().control;
+    }
+
     pub fn is_on(&self) -> bool {
-        return self.state.on;
+        return self.state().on;
     }
 
     pub fn brightness(&self) -> u8 {
-        return self.state.brightness;
+        return self.state().brightness;
     }
 
     pub fn color_hs(&self) -> (f32, f32) {
-        return self.state.color_hs;
+        return self.state().color_hs;
     }
 
     pub fn effect(&self) -> Effect {
-        return self.state.effect;
+        return self.state().effect;
     }
 
-    /// Destructor, joins the render thread.
-    pub fn drop(&mut self) {
+    /// Access to the frames produced by the render thread.
+    pub fn monitor(&self) -> FrameMonitor {
+        return self.monitor.clone();
+    }
+
+    /// Where network receivers write frames that replace the rendered ones.
+    pub fn external_input(&self) -> ExternalInput {
+        return self.external.clone();
+    }
+
+    /// Where the audio analysis publishes the features for the audio-reactive effects.
+    pub fn audio_input(&self) -> AudioInput {
+        return self.audio.clone();
+    }
+
+    /// Fades the lamp to black and stops the render thread. This also
+    /// happens when the handle is dropped, but calling it explicitly
+    /// allows shutting down while the handle is shared, e.g. from a signal
+    /// handler. Afterwards, all methods that change the state fail.
+    pub fn shutdown(&mut self) {
         let _ = self.tx.send(RendererCommand::Shutdown);
         // This is apparently a standard idiom known as the "option dance" [1]
         // [1]: https://users.rust-lang.org/t/spawn-threads-and-join-in-destructor/1613/9
         if let Some(handle) = self.thread.take() {
             match handle.join() {
                 Ok(_) => (),
-                Err(err) => println!("error while joining: {:#?}", err),
+                Err(err) => log::error!("render thread panicked: {:?}", err),
             }
         }
     }
 } // impl handle
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
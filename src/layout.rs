@@ -0,0 +1,53 @@
+/// The physical arrangement of the strip into consecutive strands.
+///
+/// For visualization, every strand is drawn as one column of a grid,
+/// with the first LED of each strand in the top row. Strands that are
+/// shorter than the longest one leave empty cells at the bottom.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    strands: Vec<usize>,
+}
+
+impl Layout {
+    pub fn new(strands: Vec<usize>) -> Layout {
+        return Layout { strands: strands };
+    }
+
+    pub fn strands(&self) -> &[usize] {
+        return &self.strands;
+    }
+
+    /// Total number of LEDs on the strip.
+    pub fn len(&self) -> usize {
+        return self.strands.iter().sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Number of columns of the grid, i.e. the number of strands.
+    pub fn width(&self) -> usize {
+        return self.strands.len();
+    }
+
+    /// Number of rows of the grid, i.e. the length of the longest strand.
+    pub fn height(&self) -> usize {
+        return self.strands.iter().copied().max().unwrap_or(0);
+    }
+
+    /// Arranges a frame of 0x00RRGGBB values into rows of the grid.
+    /// Cells that don't correspond to an LED, or that are not covered
+    /// by a short frame, are `None`.
+    pub fn grid(&self, colors: &[u32]) -> Vec<Vec<Option<u32>>> {
+        let mut rows = vec![vec![None; self.width()]; self.height()];
+        let mut offset = 0;
+        for (column, strand) in self.strands.iter().enumerate() {
+            for (row, cells) in rows.iter_mut().enumerate().take(*strand) {
+                cells[column] = colors.get(offset + row).copied();
+            }
+            offset += strand;
+        }
+        return rows;
+    }
+}
@@ -1,8 +1,66 @@
 use anyhow::anyhow;
+use anyhow::bail;
+
+use crate::config::TransformConfig;
+
+/// The corrections of a `TransformConfig`, precomputed
+/// as one lookup table per color channel.
+#[derive(Clone, Debug)]
+pub struct ColorTransform {
+    // Indexed by channel (red, green, blue) and input value.
+    tables: [[u8; 256]; 3],
+    index_map: Vec<usize>,
+}
+
+impl ColorTransform {
+    /// Checks the config against the number of LEDs of the strip.
+    pub fn new(config: &TransformConfig, leds_count: usize) -> anyhow::Result<ColorTransform> {
+        if let Some(index) = config.index_map.iter().find(|index| **index >= leds_count) {
+            bail!("index {} in 'transform.index_map' is out of range for {} LEDs", index, leds_count);
+        }
+        let mut tables = [[0; 256]; 3];
+        for (channel, table) in tables.iter_mut().enumerate() {
+            // Gamma correction first, so that the scaling below
+            // is linear in the actual light output.
+            let scale = config.white_balance[channel] * config.max_brightness as f32;
+            for (value, entry) in table.iter_mut().enumerate() {
+                let linear = (value as f32 / 255.0).powf(config.gamma);
+                *entry = (linear * scale).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        return Ok(ColorTransform {
+            tables: tables,
+            index_map: config.index_map.clone(),
+        });
+    }
+
+    /// Leaves all colors unchanged.
+    pub fn identity() -> ColorTransform {
+        let mut tables = [[0; 256]; 3];
+        for table in tables.iter_mut() {
+            for (value, entry) in table.iter_mut().enumerate() {
+                *entry = value as u8;
+            }
+        }
+        return ColorTransform {
+            tables: tables,
+            index_map: Vec::new(),
+        };
+    }
+
+    // Transforms a color in 0x00RRGGBB format.
+    fn color(&self, color: u32) -> u32 {
+        let r = self.tables[0][((color >> 16) & 0xff) as usize] as u32;
+        let g = self.tables[1][((color >> 8) & 0xff) as usize] as u32;
+        let b = self.tables[2][(color & 0xff) as usize] as u32;
+        return (r << 16) | (g << 8) | b;
+    }
+}
 
 pub struct DeviceController {
     hw: ws281x::handle::Handle,
     channel: usize,
+    transform: ColorTransform,
 }
 
 // Declare the state to be safe to send between threads. (ie., that it does not contain thread_local data)
@@ -56,24 +114,39 @@ impl DeviceController {
         return Ok(DeviceController {
             hw: handler,
             channel: rpi_channel,
+            transform: ColorTransform::identity(),
         });
     }
 
+    /// Sets the corrections that `apply` performs on every frame.
+    pub fn set_transform(&mut self, transform: ColorTransform) {
+        self.transform = transform;
+    }
+
     pub fn apply(&mut self, led_colors: &[u32]) {
-        let bound = led_colors.len();
-        for (i, led) in self
-            .hw
-            .channel_mut(self.channel)
-            .leds_mut()
-            .iter_mut()
-            .enumerate()
-        {
-            if i >= bound {
-                break;
+        let leds = self.hw.channel_mut(self.channel).leds_mut();
+        if self.transform.index_map.is_empty() {
+            for (led, color) in leds.iter_mut().zip(led_colors) {
+                *led = self.transform.color(*color);
+            }
+        } else {
+            // LEDs that no color is mapped to would keep their last color.
+            leds.fill(0);
+            for (index, color) in self.transform.index_map.iter().zip(led_colors) {
+                leds[*index] = self.transform.color(*color);
             }
-            *led = led_colors[i];
         }
         self.hw.render().unwrap();
         self.hw.wait().unwrap();
     }
+
+    /// Turns off all LEDs, e.g. before shutting down.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        for led in self.hw.channel_mut(self.channel).leds_mut().iter_mut() {
+            *led = 0;
+        }
+        self.hw.render().map_err(|_e| anyhow!("failed to render"))?;
+        self.hw.wait().map_err(|_e| anyhow!("failed to render"))?;
+        return Ok(());
+    }
 }
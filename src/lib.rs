@@ -6,10 +6,26 @@
 // Implementing Binary:                    firelight-daemon                 (lib)              firelight-rest         homeassistant
 //                                                                                             debug-shell            actual human
 
+pub mod audio;
+pub mod auth;
+pub mod config;
 pub mod daemon;
+pub mod dmx;
 pub mod firelight_api;
+pub mod layout;
 pub mod ledstrip;
+pub mod logging;
+pub mod metrics;
+pub mod opc;
+pub mod openapi;
+pub mod presets;
+pub mod preview;
 pub mod renderer;
+pub mod schedule;
+pub mod storage;
+pub mod sun;
+pub mod systemd;
+pub mod wled;
 pub mod args;
 
 pub use firelight_api::*;
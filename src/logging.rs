@@ -0,0 +1,228 @@
+// Log output of all binaries, behind the `log` facade. Messages are filtered
+// by level per module, and written as text or JSON lines to stderr, or
+// directly to the systemd journal. Besides the message, every entry carries
+// the instance name and the key-value pairs given at the call site, e.g.
+// `log::info!(client = id; "connected")`.
+
+use std::fmt::Write;
+use std::io::Write as _;
+use std::os::unix::net::UnixDatagram;
+
+use anyhow::anyhow;
+use chrono::Local;
+use log::kv::VisitSource;
+use log::Level;
+use log::LevelFilter;
+use log::Log;
+use log::Metadata;
+use log::Record;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::args::LogArgs;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Native journal entries, with the context as separate fields.
+    Journald,
+}
+
+/// Which levels are logged, as the default level followed by overrides
+/// for modules and their submodules, e.g. `warn,firelight::renderer=debug`.
+#[derive(Clone, Debug)]
+struct Filter {
+    default: LevelFilter,
+    // Sorted by decreasing length, so that the most specific target matches first.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> anyhow::Result<Filter> {
+        let level = |text: &str| {
+            text.trim().parse::<LevelFilter>().map_err(|_| anyhow!("invalid log level '{}'", text.trim()))
+        };
+        let mut filter = Filter { default: LevelFilter::Info, targets: Vec::new() };
+        for directive in spec.split(',').filter(|directive| !directive.trim().is_empty()) {
+            match directive.split_once('=') {
+                Some((target, text)) => filter.targets.push((target.trim().to_string(), level(text)?)),
+                None => filter.default = level(directive)?,
+            }
+        }
+        filter.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        return Ok(filter);
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        for (prefix, level) in &self.targets {
+            let rest = target.strip_prefix(prefix.as_str());
+            if matches!(rest, Some(rest) if rest.is_empty() || rest.starts_with("::")) {
+                return *level;
+            }
+        }
+        return self.default;
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        return self.targets.iter().map(|(_, level)| *level).fold(self.default, Ord::max);
+    }
+}
+
+/// Collects the key-value pairs of a record.
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        return Ok(());
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+    instance: Option<String>,
+    // Used as the syslog identifier in the journal.
+    program: String,
+    journal: Option<UnixDatagram>,
+}
+
+impl Logger {
+    fn fields(&self, record: &Record) -> Vec<(String, String)> {
+        let mut fields = Fields::default();
+        if let Some(instance) = &self.instance {
+            fields.0.push(("instance".to_string(), instance.clone()));
+        }
+        // Only fails if the visitor does, which ours doesn't.
+        let _ = record.key_values().visit(&mut fields);
+        return fields.0;
+    }
+
+    fn text(&self, record: &Record, fields: &[(String, String)]) -> String {
+        let mut line = format!(
+            "{} {:<5} {}: {}",
+            Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        for (key, value) in fields {
+            if value.contains(char::is_whitespace) || value.is_empty() {
+                let _ = write!(line, " {}={:?}", key, value);
+            } else {
+                let _ = write!(line, " {}={}", key, value);
+            }
+        }
+        return line;
+    }
+
+    fn json(&self, record: &Record, fields: &[(String, String)]) -> String {
+        let mut object = Map::new();
+        object.insert("time".to_string(), Value::from(Local::now().to_rfc3339()));
+        object.insert("level".to_string(), Value::from(record.level().as_str()));
+        object.insert("target".to_string(), Value::from(record.target()));
+        object.insert("message".to_string(), Value::from(record.args().to_string()));
+        for (key, value) in fields {
+            object.insert(key.clone(), Value::from(value.as_str()));
+        }
+        return Value::Object(object).to_string();
+    }
+
+    /// Encodes an entry in the native journal protocol, see `systemd.journal-fields(7)`.
+    fn journal_entry(&self, record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        let mut entry = Vec::new();
+        let mut add = |key: &str, value: &str| {
+            // Journal field names consist of uppercase letters, digits and underscores.
+            let key: String = key.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            let key = key.trim_start_matches('_');
+            if value.contains('\n') {
+                entry.extend_from_slice(key.as_bytes());
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                entry.extend_from_slice(key.as_bytes());
+                entry.push(b'=');
+            }
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        };
+        add("MESSAGE", &record.args().to_string());
+        add("PRIORITY", priority);
+        add("SYSLOG_IDENTIFIER", &self.program);
+        add("TARGET", record.target());
+        for (key, value) in fields {
+            add(key, value);
+        }
+        return entry;
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.level() <= self.filter.level(metadata.target());
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let fields = self.fields(record);
+        let line = match self.format {
+            LogFormat::Text => self.text(record, &fields),
+            LogFormat::Json => self.json(record, &fields),
+            LogFormat::Journald => {
+                let entry = self.journal_entry(record, &fields);
+                let sent = self.journal.as_ref().map(|journal| journal.send_to(&entry, JOURNAL_SOCKET));
+                if let Some(Ok(_)) = sent {
+                    return;
+                }
+                // Don't lose the message if the journal isn't reachable.
+                self.text(record, &fields)
+            },
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Installs the logger for the whole process. `instance` is added
+/// as context to every message if given.
+pub fn init(args: &LogArgs, instance: Option<&str>) -> anyhow::Result<()> {
+    let filter = Filter::parse(&args.log_level)?;
+    let program = std::env::args()
+        .next()
+        .and_then(|path| path.rsplit('/').next().map(str::to_string))
+        .unwrap_or_else(|| "firelight".to_string());
+    let journal = match args.log_format {
+        LogFormat::Journald => Some(UnixDatagram::unbound()?),
+        _ => None,
+    };
+    let max_level = filter.max_level();
+    let logger = Logger {
+        filter: filter,
+        format: args.log_format,
+        instance: instance.map(str::to_string),
+        program: program,
+        journal: journal,
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| anyhow!("couldn't install logger: {}", e))?;
+    log::set_max_level(max_level);
+    return Ok(());
+}
@@ -0,0 +1,85 @@
+// Metrics in the Prometheus text format, and an estimate of the power
+// drawn by the strip, so that many lamps can be monitored centrally.
+
+use std::fmt::Write;
+
+// Typical values for WS2811/WS2812 LEDs at 5V: each fully lit color
+// channel draws about 20mA, and the driver of a dark LED about 1mA.
+/// The supply voltage of the strip.
+pub const VOLTS: f64 = 5.0;
+const AMPS_PER_CHANNEL: f64 = 0.02;
+const IDLE_AMPS_PER_LED: f64 = 0.001;
+
+/// Estimates the power in watts that the strip draws while showing `colors`.
+/// This doesn't know about corrections applied by the daemon, like a
+/// brightness cap, so it's an upper bound in that case.
+pub fn estimated_watts(colors: &[u32]) -> f64 {
+    let mut amps = 0.0;
+    for color in colors {
+        let channels = ((color >> 16) & 0xff) + ((color >> 8) & 0xff) + (color & 0xff);
+        amps += IDLE_AMPS_PER_LED + AMPS_PER_CHANNEL * channels as f64 / 255.0;
+    }
+    return amps * VOLTS;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    fn name(&self) -> &'static str {
+        return match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        };
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+/// Collects metrics into the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics::default();
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        // Writing to a String can't fail.
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    /// Adds a metric with a single value.
+    pub fn add(&mut self, name: &str, kind: MetricKind, help: &str, value: f64) {
+        self.header(name, kind.name(), help);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+
+    /// Adds a metric with one value per value of the label.
+    pub fn add_labeled(&mut self, name: &str, kind: MetricKind, help: &str, label: &str, values: &[(String, f64)]) {
+        self.header(name, kind.name(), help);
+        for (label_value, value) in values {
+            let _ = writeln!(self.text, "{}{{{}=\"{}\"}} {}", name, label, escape_label(label_value), value);
+        }
+    }
+
+    /// Adds a summary without quantiles, i.e. the sum and the number of observations.
+    pub fn add_summary(&mut self, name: &str, help: &str, sum: f64, count: u64) {
+        self.header(name, "summary", help);
+        let _ = writeln!(self.text, "{}_sum {}", name, sum);
+        let _ = writeln!(self.text, "{}_count {}", name, count);
+    }
+
+    pub fn finish(self) -> String {
+        return self.text;
+    }
+}
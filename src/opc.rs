@@ -0,0 +1,118 @@
+// A server for Open Pixel Control, the protocol of Fadecandy, which many
+// generative art tools can send. Messages that set pixel colors are shown
+// instead of the rendered frames for as long as a client keeps sending.
+// See http://openpixelcontrol.org/ for the protocol.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::net::TcpListener;
+use std::net::TcpStream;
+
+use crate::layout::Layout;
+use crate::renderer::ExternalInput;
+use crate::renderer::EXTERNAL_TIMEOUT;
+
+const SET_PIXEL_COLOURS: u8 = 0;
+
+/// A message of the protocol. Commands other than setting pixel colors,
+/// like system exclusive messages, are read but ignored.
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub channel: u8,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+/// Reads the next message, or returns `None` if the client closed the connection.
+pub fn read_message(stream: &mut impl Read) -> std::io::Result<Option<Message>> {
+    let mut header = [0u8; 4];
+    match stream.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut data = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+    stream.read_exact(&mut data)?;
+    return Ok(Some(Message {
+        channel: header[0],
+        command: header[1],
+        data: data,
+    }));
+}
+
+/// Which LEDs an OPC channel addresses. Like with a Fadecandy server, channel 0
+/// covers the whole strip, and channel `n` covers the `n`-th strand.
+#[derive(Clone, Debug)]
+pub struct ChannelMap {
+    // Offset and length of every strand.
+    strands: Vec<(usize, usize)>,
+    len: usize,
+}
+
+impl ChannelMap {
+    pub fn new(layout: &Layout) -> ChannelMap {
+        let mut strands = Vec::new();
+        let mut offset = 0;
+        for strand in layout.strands() {
+            strands.push((offset, *strand));
+            offset += strand;
+        }
+        return ChannelMap { strands: strands, len: layout.len() };
+    }
+
+    /// The offset and length of the LEDs of the channel, or `None` if there is no such strand.
+    pub fn segment(&self, channel: u8) -> Option<(usize, usize)> {
+        if channel == 0 {
+            return Some((0, self.len));
+        }
+        return self.strands.get(channel as usize - 1).copied();
+    }
+}
+
+fn handle_client(mut stream: TcpStream, map: &ChannelMap, input: &ExternalInput, source: &str) -> std::io::Result<()> {
+    while let Some(message) = read_message(&mut stream)? {
+        if message.command != SET_PIXEL_COLOURS {
+            continue;
+        }
+        let (offset, len) = match map.segment(message.channel) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let colors: Vec<u32> = message.data
+            .chunks_exact(3)
+            .take(len)
+            .map(|rgb| ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | rgb[2] as u32)
+            .collect();
+        input.update(source, offset, &colors, EXTERNAL_TIMEOUT);
+    }
+    return Ok(());
+}
+
+/// Accepts clients on `listener` until an error occurs, each in its own thread.
+pub fn serve(listener: TcpListener, layout: &Layout, input: ExternalInput) -> anyhow::Result<()> {
+    log::info!("accepting OPC clients on {}", listener.local_addr()?);
+    let map = ChannelMap::new(layout);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("couldn't accept OPC client: {}", e);
+                continue;
+            },
+        };
+        let client = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown".to_string());
+        let map = map.clone();
+        let input = input.clone();
+        std::thread::spawn(move || {
+            log::info!(client = client.as_str(); "new OPC client");
+            let source = format!("opc from {}", client);
+            match handle_client(stream, &map, &input, &source) {
+                Ok(()) => log::info!(client = client.as_str(); "OPC client disconnected"),
+                Err(e) => log::warn!(client = client.as_str(); "OPC client failed: {}", e),
+            }
+            // Don't keep the last frame of the client for the full timeout.
+            input.release(&source);
+        });
+    }
+    return Ok(());
+}
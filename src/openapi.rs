@@ -0,0 +1,232 @@
+// Builds the OpenAPI description of the REST API. The schemas of the
+// request and response bodies are derived from the Rust types, so they
+// can't drift from what the server actually accepts and returns. The
+// paths and methods are listed by hand, and a test of firelight-rest
+// checks them against its router.
+
+use schemars::gen::SchemaGenerator;
+use schemars::gen::SchemaSettings;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+struct Parameter {
+    name: &'static str,
+    // "path" or "query"
+    location: &'static str,
+    description: &'static str,
+    schema: SchemaFn,
+}
+
+struct Content {
+    content_type: &'static str,
+    schema: Option<SchemaFn>,
+}
+
+struct Response {
+    status_code: u16,
+    description: &'static str,
+    content: Option<Content>,
+}
+
+/// One endpoint of the API, described with the builder methods.
+pub struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Parameter>,
+    body: Option<Content>,
+    responses: Vec<Response>,
+    anonymous: bool,
+}
+
+impl Operation {
+    /// Path parameters are written like `/presets/{name}`.
+    pub fn new(method: &'static str, path: &'static str, summary: &'static str) -> Operation {
+        return Operation {
+            method: method,
+            path: path,
+            summary: summary,
+            parameters: Vec::new(),
+            body: None,
+            responses: Vec::new(),
+            anonymous: false,
+        };
+    }
+
+    pub fn path_param<T: JsonSchema>(mut self, name: &'static str, description: &'static str) -> Operation {
+        self.parameters.push(Parameter {
+            name: name,
+            location: "path",
+            description: description,
+            schema: SchemaGenerator::subschema_for::<T>,
+        });
+        return self;
+    }
+
+    pub fn query_param<T: JsonSchema>(mut self, name: &'static str, description: &'static str) -> Operation {
+        self.parameters.push(Parameter {
+            name: name,
+            location: "query",
+            description: description,
+            schema: SchemaGenerator::subschema_for::<T>,
+        });
+        return self;
+    }
+
+    pub fn json_body<T: JsonSchema>(mut self) -> Operation {
+        self.body = Some(Content {
+            content_type: "application/json",
+            schema: Some(SchemaGenerator::subschema_for::<T>),
+        });
+        return self;
+    }
+
+    pub fn form_body<T: JsonSchema>(mut self) -> Operation {
+        self.body = Some(Content {
+            content_type: "application/x-www-form-urlencoded",
+            schema: Some(SchemaGenerator::subschema_for::<T>),
+        });
+        return self;
+    }
+
+    /// A successful response with a JSON body.
+    pub fn json_response<T: JsonSchema>(self, description: &'static str) -> Operation {
+        return self.json_status::<T>(200, description);
+    }
+
+    /// A response with a JSON body, usually an error.
+    pub fn json_status<T: JsonSchema>(mut self, status_code: u16, description: &'static str) -> Operation {
+        self.responses.push(Response {
+            status_code: status_code,
+            description: description,
+            content: Some(Content {
+                content_type: "application/json",
+                schema: Some(SchemaGenerator::subschema_for::<T>),
+            }),
+        });
+        return self;
+    }
+
+    /// A response whose body isn't described by a Rust type, e.g. an image.
+    pub fn response(mut self, status_code: u16, content_type: &'static str, description: &'static str) -> Operation {
+        self.responses.push(Response {
+            status_code: status_code,
+            description: description,
+            content: Some(Content { content_type: content_type, schema: None }),
+        });
+        return self;
+    }
+
+    /// A response without a body.
+    pub fn empty_response(mut self, status_code: u16, description: &'static str) -> Operation {
+        self.responses.push(Response {
+            status_code: status_code,
+            description: description,
+            content: None,
+        });
+        return self;
+    }
+
+    /// Marks the operation as accessible without credentials.
+    pub fn anonymous(mut self) -> Operation {
+        self.anonymous = true;
+        return self;
+    }
+}
+
+fn content_json(content: &Content, generator: &mut SchemaGenerator) -> Value {
+    let schema = match content.schema {
+        Some(schema) => serde_json::to_value(schema(generator)).unwrap_or(Value::Null),
+        None => json!({}),
+    };
+    return json!({ content.content_type: { "schema": schema } });
+}
+
+fn operation_json(operation: &Operation, generator: &mut SchemaGenerator, authentication: bool) -> Value {
+    let mut result = Map::new();
+    result.insert("summary".to_string(), json!(operation.summary));
+    let parameters: Vec<Value> = operation.parameters
+        .iter()
+        .map(|parameter| json!({
+            "name": parameter.name,
+            "in": parameter.location,
+            "required": parameter.location == "path",
+            "description": parameter.description,
+            "schema": serde_json::to_value((parameter.schema)(generator)).unwrap_or(Value::Null),
+        }))
+        .collect();
+    if !parameters.is_empty() {
+        result.insert("parameters".to_string(), Value::Array(parameters));
+    }
+    if let Some(body) = &operation.body {
+        result.insert("requestBody".to_string(), json!({
+            "required": true,
+            "content": content_json(body, generator),
+        }));
+    }
+    let mut responses = Map::new();
+    for response in &operation.responses {
+        // Responses with the same status code but different
+        // content types are merged into one entry.
+        let value = responses
+            .entry(response.status_code.to_string())
+            .or_insert_with(|| json!({ "description": response.description, "content": {} }));
+        if let Some(content) = &response.content {
+            if let (Value::Object(all), Value::Object(new)) = (&mut value["content"], content_json(content, generator)) {
+                all.extend(new);
+            }
+        }
+    }
+    for value in responses.values_mut() {
+        if let Value::Object(value) = value {
+            if matches!(value.get("content"), Some(Value::Object(content)) if content.is_empty()) {
+                value.remove("content");
+            }
+        }
+    }
+    if authentication && !operation.anonymous {
+        responses.insert("401".to_string(), json!({ "description": "Missing or unknown credentials." }));
+        if operation.method != "GET" {
+            responses.insert("403".to_string(), json!({ "description": "The credentials only have the read scope." }));
+        }
+    }
+    result.insert("responses".to_string(), Value::Object(responses));
+    if operation.anonymous {
+        result.insert("security".to_string(), json!([]));
+    }
+    return Value::Object(result);
+}
+
+/// The OpenAPI 3 document for the given operations. If `authentication` is
+/// set, all operations not marked as anonymous require a token or basic auth.
+pub fn document(title: &str, version: &str, operations: &[Operation], authentication: bool) -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+    for operation in operations {
+        let item = paths.entry(operation.path.to_string()).or_insert_with(|| json!({}));
+        item[operation.method.to_lowercase()] = operation_json(operation, &mut generator, authentication);
+    }
+    let schemas: Map<String, Value> = generator.take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap_or(Value::Null)))
+        .collect();
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": { "title": title, "version": version },
+        "paths": paths,
+        "components": { "schemas": schemas },
+    });
+    if authentication {
+        document["components"]["securitySchemes"] = json!({
+            "bearer": { "type": "http", "scheme": "bearer" },
+            "basic": { "type": "http", "scheme": "basic" },
+        });
+        document["security"] = json!([{ "bearer": [] }, { "basic": [] }]);
+    }
+    return document;
+}
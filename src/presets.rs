@@ -0,0 +1,135 @@
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::firelight_api::Control;
+use crate::firelight_api::Effect;
+use crate::storage;
+
+/// A named, complete renderer state like "campfire at 40%".
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Preset {
+    /// Assigned by the `PresetStore` and kept when the preset is replaced,
+    /// so that e.g. WLED clients can refer to a preset by a stable number.
+    /// Ignored when storing a preset.
+    #[serde(default)]
+    pub id: u32,
+    pub name: String,
+    pub control: Control,
+}
+
+impl Preset {
+    /// Fails if the name or a value of the state is invalid.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_name(&self.name)?;
+        return self.control.validate();
+    }
+}
+
+/// The list of presets, persisted as a JSON file.
+///
+/// Presets keep the order in which they were created, so that
+/// they show up in a stable order in user interfaces.
+pub struct PresetStore {
+    // If `None`, presets are only kept in memory.
+    path: Option<PathBuf>,
+    presets: Vec<Preset>,
+}
+
+impl PresetStore {
+    /// Loads the presets from `path`. A missing file is not an
+    /// error, it will be created when the first preset is stored.
+    pub fn open(path: Option<PathBuf>) -> anyhow::Result<PresetStore> {
+        let mut presets: Vec<Preset> = match &path {
+            Some(path) => storage::load_json(path)?.unwrap_or_default(),
+            None => Vec::new(),
+        };
+        for preset in &presets {
+            preset.validate().map_err(|e| anyhow!("invalid preset '{}': {}", preset.name, e))?;
+        }
+        // Files of older versions have no ids, where WLED clients knew
+        // the presets by their position, starting at 1.
+        let mut last_id = presets.iter().map(|preset| preset.id).max().unwrap_or(0);
+        for preset in presets.iter_mut().filter(|preset| preset.id == 0) {
+            last_id += 1;
+            preset.id = last_id;
+        }
+        return Ok(PresetStore {
+            path: path,
+            presets: presets,
+        });
+    }
+
+    pub fn list(&self) -> &[Preset] {
+        return &self.presets;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        return self.presets.iter().find(|preset| preset.name == name);
+    }
+
+    pub fn get_by_id(&self, id: u32) -> Option<&Preset> {
+        return self.presets.iter().find(|preset| preset.id == id);
+    }
+
+    /// Adds a preset, or replaces the existing one with the same name, and
+    /// returns it with its id. Fails if the preset is invalid, see
+    /// `Preset::validate()`, or if it couldn't be stored, in which case
+    /// nothing changes.
+    pub fn insert(&mut self, mut preset: Preset) -> anyhow::Result<Preset> {
+        preset.validate()?;
+        let mut presets = self.presets.clone();
+        match presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(existing) => {
+                preset.id = existing.id;
+                *existing = preset.clone();
+            },
+            None => {
+                preset.id = presets.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+                presets.push(preset.clone());
+            },
+        }
+        self.save(presets)?;
+        return Ok(preset);
+    }
+
+    /// Returns `false` if there was no preset with that name.
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<bool> {
+        let mut presets = self.presets.clone();
+        presets.retain(|preset| preset.name != name);
+        if presets.len() == self.presets.len() {
+            return Ok(false);
+        }
+        self.save(presets)?;
+        return Ok(true);
+    }
+
+    /// Stores `presets` and then replaces the list, so that the list
+    /// stays as it is in the file if storing fails.
+    fn save(&mut self, presets: Vec<Preset>) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            storage::save_json(path, &presets).map_err(|e| anyhow!("couldn't store the presets: {}", e))?;
+        }
+        self.presets = presets;
+        return Ok(());
+    }
+}
+
+/// Preset names share a namespace with the built-in effects, since
+/// both can be selected as an effect by Home Assistant.
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > 64 {
+        bail!("preset names must have between 1 and 64 characters");
+    }
+    if name.contains('/') || name.chars().any(|c| c.is_control()) {
+        bail!("preset names must not contain '/' or control characters");
+    }
+    if Effect::from_string(name).is_ok() {
+        bail!("'{}' is already the name of an effect", name);
+    }
+    return Ok(());
+}
@@ -0,0 +1,168 @@
+// Helpers to make the output of the renderer visible without
+// standing next to the lamp.
+
+use std::io::Read;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::layout::Layout;
+use crate::renderer::Frame;
+use crate::renderer::FrameMonitor;
+
+/// JSON representation of a rendered frame.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct FrameJson {
+    pub sequence: u64,
+    pub strands: Vec<usize>,
+    /// Raw colors in 0x00RRGGBB format, in strip order.
+    pub colors: Vec<u32>,
+}
+
+impl FrameJson {
+    pub fn new(frame: Frame, layout: &Layout) -> FrameJson {
+        return FrameJson {
+            sequence: frame.sequence,
+            strands: layout.strands().to_vec(),
+            colors: frame.colors,
+        };
+    }
+}
+
+fn to_rgb(color: u32) -> [u8; 3] {
+    return [(color >> 16) as u8, (color >> 8) as u8, color as u8];
+}
+
+/// Renders the frame as an RGB image with one column per strand, where every
+/// LED is drawn as a `scale`x`scale` square. Cells without an LED are left
+/// black.
+pub fn to_rgb_image(layout: &Layout, colors: &[u32], scale: usize) -> (usize, usize, Vec<u8>) {
+    let width = layout.width() * scale;
+    let height = layout.height() * scale;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for row in layout.grid(colors) {
+        let mut line = Vec::with_capacity(width * 3);
+        for cell in row {
+            let rgb = to_rgb(cell.unwrap_or(0));
+            for _ in 0..scale {
+                line.extend_from_slice(&rgb);
+            }
+        }
+        for _ in 0..scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    return (width, height, pixels);
+}
+
+/// Encodes the frame as a binary PPM image, see `to_rgb_image()` for the layout.
+pub fn to_ppm(layout: &Layout, colors: &[u32], scale: usize) -> Vec<u8> {
+    let (width, height, pixels) = to_rgb_image(layout, colors, scale);
+    let mut result = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    result.extend_from_slice(&pixels);
+    return result;
+}
+
+/// Draws the frame using 24-bit ANSI color escape sequences, one column
+/// per strand. Every character cell holds two LEDs of a strand, using the
+/// foreground color for the upper half block and the background color
+/// for the lower half.
+pub fn to_ansi(layout: &Layout, colors: &[u32]) -> String {
+    let grid = layout.grid(colors);
+    let mut result = String::new();
+    for pair in grid.chunks(2) {
+        for column in 0..layout.width() {
+            let upper = pair[0][column];
+            let lower = pair.get(1).and_then(|row| row[column]);
+            match upper {
+                Some(color) => {
+                    let [r, g, b] = to_rgb(color);
+                    result.push_str(&format!("\x1b[38;2;{};{};{}m", r, g, b));
+                }
+                None => result.push_str("\x1b[39m"),
+            }
+            match lower {
+                Some(color) => {
+                    let [r, g, b] = to_rgb(color);
+                    result.push_str(&format!("\x1b[48;2;{};{};{}m", r, g, b));
+                }
+                None => result.push_str("\x1b[49m"),
+            }
+            let block = if upper.is_some() { "\u{2580}\u{2580}" } else { "  " };
+            result.push_str(block);
+            result.push_str("\x1b[0m ");
+        }
+        result.push('\n');
+    }
+    return result;
+}
+
+/// Encodes the frame as a PNG image, see `to_rgb_image()` for the layout.
+pub fn to_png(layout: &Layout, colors: &[u32], scale: usize) -> anyhow::Result<Vec<u8>> {
+    let (width, height, pixels) = to_rgb_image(layout, colors, scale);
+    let mut result = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut result, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+    }
+    return Ok(result);
+}
+
+/// An endless stream of frames in `text/event-stream` format, where
+/// every event contains a `FrameJson`. Intended to be used as the body
+/// of a streaming HTTP response, so `read()` blocks until the next frame
+/// is due, and returns at most one event, which can be sent right away.
+pub struct FrameStream {
+    monitor: FrameMonitor,
+    layout: Layout,
+    interval: Duration,
+    last_sequence: Option<u64>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl FrameStream {
+    pub fn new(monitor: FrameMonitor, layout: Layout, fps: u32) -> FrameStream {
+        return FrameStream {
+            monitor: monitor,
+            layout: layout,
+            interval: Duration::from_millis(1000 / fps.clamp(1, 60) as u64),
+            last_sequence: None,
+            buffer: Vec::new(),
+            position: 0,
+        };
+    }
+
+    fn next_event(&mut self) -> std::io::Result<()> {
+        let mut frame = self.monitor.latest();
+        // Don't send the same frame twice, e.g. while the renderer is stalled.
+        while Some(frame.sequence) == self.last_sequence {
+            std::thread::sleep(self.interval);
+            frame = self.monitor.latest();
+        }
+        self.last_sequence = Some(frame.sequence);
+        let json = serde_json::to_string(&FrameJson::new(frame, &self.layout))?;
+        self.buffer = format!("data: {}\n\n", json).into_bytes();
+        self.position = 0;
+        return Ok(());
+    }
+}
+
+impl Read for FrameStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.buffer.len() {
+            if self.last_sequence.is_some() {
+                std::thread::sleep(self.interval);
+            }
+            self.next_event()?;
+        }
+        let n = std::cmp::min(buf.len(), self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        return Ok(n);
+    }
+}
@@ -1,67 +1,421 @@
 use noise::NoiseFn;
 use noise::Perlin;
-use std::io::Write;
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
 
-use std::os::unix::net::UnixStream;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use palette::FromColor;
 use palette::Pixel;
 
+use crate::audio::AudioFeatures;
+use crate::audio::AudioInput;
 use crate::firelight_api::Control;
 use crate::firelight_api::Effect;
+use crate::firelight_api::Status;
+use crate::firelight_api::WakeUp;
 use crate::daemon;
 
 
 pub(crate) enum RendererCommand {
     Shutdown,
     ControlMsg(Control),
+    // Start a sleep timer with the given duration, or cancel it.
+    SleepTimer(Option<Duration>),
+    // Schedule a wake-up light, or cancel it.
+    WakeUp(Option<WakeUp>),
+}
+
+pub(crate) struct SleepTimer {
+    start: SystemTime,
+    end: SystemTime,
 }
 
 // TODO: This probably shouldn't be public.
 pub(crate) struct RenderThreadData {
     pub rx: mpsc::Receiver<RendererCommand>,
 
-    pub socket: UnixStream,
+    pub connection: daemon::Connection,
     pub strands: Vec<usize>,
 
     // The last received control msg
     pub state: Control,
+
+    // Where rendered frames are published for previews.
+    pub monitor: FrameMonitor,
+
+    // Frames from network receivers, which take precedence over rendering.
+    pub external: ExternalInput,
+
+    // Features of the audio input, for the audio-reactive effects.
+    pub audio: AudioInput,
+
+    // Where we report back to the `Handle`.
+    pub status: Arc<Mutex<Status>>,
+
+    pub sleep_timer: Option<SleepTimer>,
+    pub wake_up: Option<WakeUp>,
+
+    // When the previous frame was output, to compute the frame rate.
+    pub last_output: Option<Instant>,
+}
+
+impl RenderThreadData {
+    /// Records the outcome of sending a frame to the daemon.
+    fn report_write(&self, result: std::io::Result<()>) {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        let health = &mut status.health;
+        health.reconnects = self.connection.reconnects();
+        match result {
+            Ok(()) => {
+                if !health.daemon_connected {
+                    log::info!(reconnects = health.reconnects; "sending frames to the daemon again");
+                }
+                health.daemon_connected = true;
+                health.last_frame = Some(SystemTime::now());
+            },
+            Err(e) => {
+                // Only log the first of a series of failures, not one per frame.
+                if health.daemon_connected {
+                    log::warn!("couldn't send frame to the daemon: {}", e);
+                }
+                health.daemon_connected = false;
+                health.write_errors += 1;
+                health.last_error = Some(e.to_string());
+            },
+        }
+    }
+
+    /// Records that a frame was rendered in the given time.
+    fn report_render(&mut self, duration: Duration) {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.render.rendered += 1;
+        status.render.render_time += duration;
+    }
+
+    /// Records that a frame was output, whether rendered or external.
+    fn report_output(&mut self, now: Instant) {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = &mut status.render;
+        stats.frames += 1;
+        if let Some(last_output) = self.last_output {
+            let interval = now.duration_since(last_output).as_secs_f32();
+            if interval > 0.0 {
+                // An exponential moving average, weighted by the interval
+                // so that it covers roughly the last second.
+                let weight = interval.min(1.0);
+                stats.fps += (1.0 / interval - stats.fps) * weight;
+            }
+        }
+        self.last_output = Some(now);
+    }
+
+    /// Returns the external frame that should be shown instead of a rendered one, if any.
+    fn take_external(&self) -> Option<Vec<u32>> {
+        let current = self.external.current(Instant::now());
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        let source = current.as_ref().map(|(source, _)| source.clone());
+        if source != status.external_source {
+            match &source {
+                Some(source) => log::info!(source = source.as_str(); "showing frames from {}", source),
+                None => log::info!("external frames stopped, rendering again"),
+            }
+            status.external_source = source;
+        }
+        return current.map(|(_, colors)| colors);
+    }
+
+    fn publish_status(&self) {
+        let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+        status.control = self.state;
+        status.sleep_until = self.sleep_timer.as_ref().map(|timer| timer.end);
+        status.wake_up = self.wake_up;
+    }
+
+    /// Returns the state that should be rendered right now, taking running
+    /// timers into account. Timers that expired change the state permanently.
+    fn advance_timers(&mut self, now: SystemTime) -> Control {
+        let mut control = self.state;
+        let mut changed = false;
+
+        if let Some(wake_up) = self.wake_up {
+            let start = wake_up.at.checked_sub(wake_up.duration).unwrap_or(wake_up.at);
+            if now >= wake_up.at {
+                self.state = wake_up_control(1.0);
+                self.wake_up = None;
+                self.sleep_timer = None;
+                control = self.state;
+                changed = true;
+            } else if now >= start {
+                let elapsed = now.duration_since(start).unwrap_or_default();
+                control = wake_up_control(elapsed.as_secs_f32() / wake_up.duration.as_secs_f32());
+            }
+        }
+
+        if let Some(timer) = &self.sleep_timer {
+            if now >= timer.end {
+                // Only switch off, so that turning the lamp on again
+                // restores the brightness from before the fade.
+                self.state.on = false;
+                self.sleep_timer = None;
+                control = self.state;
+                changed = true;
+            } else {
+                let total = timer.end.duration_since(timer.start).unwrap_or_default();
+                let remaining = timer.end.duration_since(now).unwrap_or_default();
+                let fraction = remaining.as_secs_f32() / total.as_secs_f32();
+                control.brightness = (control.brightness as f32 * fraction).round() as u8;
+            }
+        }
+
+        if changed {
+            self.publish_status();
+        }
+        return control;
+    }
+
+    fn handle_command(&mut self, command: RendererCommand, now: SystemTime) {
+        match command {
+            RendererCommand::Shutdown => (),
+            RendererCommand::ControlMsg(control) => {
+                self.state = control;
+                // A manual change overrides the timers. A wake-up light
+                // that hasn't started yet stays scheduled though.
+                self.sleep_timer = None;
+                if let Some(wake_up) = self.wake_up {
+                    if now + wake_up.duration >= wake_up.at {
+                        self.wake_up = None;
+                    }
+                }
+            },
+            RendererCommand::SleepTimer(duration) => {
+                self.sleep_timer = duration.map(|duration| SleepTimer {
+                    start: now,
+                    end: now + duration,
+                });
+            },
+            RendererCommand::WakeUp(wake_up) => {
+                self.wake_up = wake_up;
+            },
+        }
+        self.publish_status();
+    }
+}
+
+/// The state at some point of the wake-up light, where `progress`
+/// goes from 0.0 (dim deep red) to 1.0 (bright warm white).
+fn wake_up_control(progress: f32) -> Control {
+    let progress = progress.clamp(0.0, 1.0);
+    let mut control = Control::default();
+    control.on = true;
+    control.effect = Effect::Static;
+    // Our eyes are much more sensitive to changes at low brightness,
+    // so start slowly and speed up towards the end.
+    control.brightness = (4.0 + 216.0 * progress * progress) as u8;
+    control.color_hs = (35.0 * progress, 100.0 - 20.0 * progress);
+    return control;
+}
+
+/// A frame as it was sent to the daemon.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    /// Incremented by one for every rendered frame.
+    pub sequence: u64,
+
+    /// Raw colors in 0x00RRGGBB format, in strip order.
+    pub colors: Vec<u32>,
+}
+
+/// Gives access to the most recent frame produced by the render thread.
+/// Cheap to clone, all clones observe the same render thread.
+#[derive(Clone, Debug, Default)]
+pub struct FrameMonitor {
+    frame: Arc<Mutex<Frame>>,
+}
+
+impl FrameMonitor {
+    fn publish(&self, colors: &[u32]) {
+        // A poisoned lock only means that a reader panicked,
+        // the frame itself is still valid.
+        let mut frame = self.frame.lock().unwrap_or_else(|e| e.into_inner());
+        frame.sequence += 1;
+        frame.colors.clear();
+        frame.colors.extend_from_slice(colors);
+    }
+
+    pub fn latest(&self) -> Frame {
+        return self.frame.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    }
+}
+
+/// How long external frames are shown after the last update, unless the
+/// source asks for a different time. Matches the data loss timeout of E1.31.
+pub const EXTERNAL_TIMEOUT: Duration = Duration::from_millis(2500);
+
+#[derive(Clone, Debug, Default)]
+struct ExternalFrame {
+    source: String,
+    colors: Vec<u32>,
+    // The renderer takes over again if no update arrives until then.
+    expires: Option<Instant>,
+}
+
+/// Lets receivers for network protocols, like E1.31, take over the strip from
+/// the renderer for as long as they keep sending. Cheap to clone, all clones
+/// write to the same frame.
+#[derive(Clone, Debug)]
+pub struct ExternalInput {
+    frame: Arc<Mutex<ExternalFrame>>,
+    len: usize,
+}
+
+impl ExternalInput {
+    pub(crate) fn new(len: usize) -> ExternalInput {
+        return ExternalInput {
+            frame: Arc::new(Mutex::new(ExternalFrame::default())),
+            len: len,
+        };
+    }
+
+    /// Number of LEDs on the strip.
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    /// Sets the LEDs starting at `offset` to the given 0x00RRGGBB colors, and
+    /// shows the external frame instead of rendered ones until `timeout` passed
+    /// without another update. Colors beyond the end of the strip are ignored.
+    /// LEDs that weren't set since the external frames started are black.
+    pub fn update(&self, source: &str, offset: usize, colors: &[u32], timeout: Duration) {
+        let now = Instant::now();
+        let mut frame = self.frame.lock().unwrap_or_else(|e| e.into_inner());
+        if !matches!(frame.expires, Some(expires) if now < expires) {
+            frame.colors = vec![0; self.len];
+        }
+        if offset < self.len {
+            let end = (offset + colors.len()).min(self.len);
+            frame.colors[offset..end].copy_from_slice(&colors[..end - offset]);
+        }
+        if frame.source != source {
+            frame.source = source.to_string();
+        }
+        // Saturates for timeouts that are meant to be infinite.
+        frame.expires = Some(now.checked_add(timeout).unwrap_or(now + Duration::from_secs(365 * 24 * 3600)));
+    }
+
+    /// Gives the strip back to the renderer right away, if it's
+    /// currently showing frames from `source`.
+    pub fn release(&self, source: &str) {
+        let mut frame = self.frame.lock().unwrap_or_else(|e| e.into_inner());
+        if frame.source == source {
+            frame.expires = None;
+        }
+    }
+
+    /// Gives the strip back to the renderer right away, whatever the source.
+    pub fn stop(&self) {
+        self.frame.lock().unwrap_or_else(|e| e.into_inner()).expires = None;
+    }
+
+    /// The source and colors of the external frame, unless it expired.
+    fn current(&self, now: Instant) -> Option<(String, Vec<u32>)> {
+        let frame = self.frame.lock().unwrap_or_else(|e| e.into_inner());
+        if !matches!(frame.expires, Some(expires) if now < expires) {
+            return None;
+        }
+        return Some((frame.source.clone(), frame.colors.clone()));
+    }
+}
+
+const FRAME_DURATION: Duration = Duration::from_millis(1000 / 60);
+
+// Number of frames used to fade to black when shutting down.
+const SHUTDOWN_FADE_FRAMES: usize = 30;
+
+/// Renders one frame, with all colors scaled by `fade` in [0.0, 1.0].
+fn render_frame(state: &Control, t: f64, strands: &Vec<usize>, audio: &AudioFeatures, fade: f32) -> Vec<u32> {
+    let color_hsl = palette::Hsl::new(state.color_hs.0, state.color_hs.1 / 100., state.brightness as f32 / 255.);
+    let color_rgb = palette::Srgb::from_color(color_hsl);
+    let colors = match state.effect {
+        Effect::Static => render_static(t, color_rgb, strands),
+        Effect::Fire => render_fire(t, color_rgb, strands),
+        Effect::AudioFire => render_audio_fire(t, color_rgb, strands, audio),
+        Effect::Vu => render_vu(color_rgb, strands, audio),
+        Effect::Spectrum => render_spectrum(color_rgb, strands, audio),
+    };
+    let mut out = Vec::new();
+    for original_color in colors {
+        let color = if state.on {
+            original_color.scaled(fade).to_u32_rgb()
+        } else {
+            0
+        };
+        out.push(color);
+    }
+    return out;
+}
+
+impl RenderThreadData {
+    fn output(&mut self, out: &mut [u32]) {
+        self.report_output(Instant::now());
+        self.monitor.publish(out);
+        // While the daemon is away, frames are still rendered and
+        // published, and state changes are still accepted.
+        if let Some(result) = self.connection.send(out) {
+            self.report_write(result);
+        }
+    }
 }
 
 pub(crate) fn render_thread(mut data: RenderThreadData) -> () {
     let mut t = 0.0;
     let delta = 0.01;
     loop {
-        t += delta;
-        let color_hsl = palette::Hsl::new(data.state.color_hs.0, data.state.color_hs.1 / 100., data.state.brightness as f32 / 255.);
-        let color_rgb = palette::Srgb::from_color(color_hsl);
-        let colors = match data.state.effect {
-            Effect::Static => render_static(t, color_rgb, &data.strands),
-            Effect::Fire => render_fire(t, color_rgb, &data.strands),
+        let state = data.advance_timers(SystemTime::now());
+        t += delta * state.speed as f64;
+        let mut out = match data.take_external() {
+            Some(colors) => colors,
+            None => {
+                let started = Instant::now();
+                let out = render_frame(&state, t, &data.strands, &data.audio.latest(), 1.0);
+                data.report_render(started.elapsed());
+                out
+            },
         };
-        let mut out = Vec::new();
-        for original_color in colors {
-            let color = if data.state.on {
-                original_color.to_u32_rgb()
-            } else {
-                0
-            };
-            out.push(color);
-        }
-        let _ = data.socket.write(daemon::as_bytes(&mut out[..]));
+        data.output(&mut out);
 
         // TODO: Use a separate timer thread for a stable clock pulse
-        let msg = data.rx.recv_timeout(Duration::from_millis(1000 / 60));
+        let msg = data.rx.recv_timeout(FRAME_DURATION);
         match msg {
             Ok(RendererCommand::Shutdown) => break,
-            Ok(RendererCommand::ControlMsg(control)) => {
-                data.state = control;
-            },
-            Err(_) => continue,
+            Ok(command) => data.handle_command(command, SystemTime::now()),
+            // All handles are gone without saying goodbye.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
         }
     }
+
+    // Fade to black instead of leaving the strip frozen on the last frame.
+    let state = data.advance_timers(SystemTime::now());
+    if state.on {
+        for i in 1..SHUTDOWN_FADE_FRAMES {
+            t += delta * state.speed as f64;
+            let fade = 1.0 - i as f32 / SHUTDOWN_FADE_FRAMES as f32;
+            let mut out = render_frame(&state, t, &data.strands, &data.audio.latest(), fade);
+            data.output(&mut out);
+            std::thread::sleep(FRAME_DURATION);
+        }
+    }
+    let off = Control { on: false, ..state };
+    let mut out = render_frame(&off, t, &data.strands, &AudioFeatures::default(), 0.0);
+    data.output(&mut out);
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -89,6 +443,14 @@ impl LedColor {
         }
     }
 
+    // Multiply all channels with a factor in [0.0, 1.0].
+    pub fn scaled(&self, factor: f32) -> LedColor {
+        let scale = |x: u8| (x as f32 * factor).round().clamp(0.0, 255.0) as u8;
+        return LedColor {
+            data: [scale(self.data[0]), scale(self.data[1]), scale(self.data[2])],
+        };
+    }
+
     // Render as 0x00RRGGBB.
     pub fn to_u32_rgb(&self) -> u32 {
         return ((self.data[0] as u32) << 16)
@@ -121,6 +483,69 @@ fn render_fire(t: f64, color_rgb: palette::Srgb, strands: &Vec<usize>) -> Vec<Le
     return result;
 }
 
+/// Lights the last `lit` LEDs of a strand, which are at the bottom of the lamp.
+fn fill_strand(result: &mut Vec<LedColor>, strand: usize, lit: usize, color: LedColor) {
+    let lit = lit.min(strand);
+    for _ in lit..strand {
+        result.push(LedColor::from_u32_rgb(0x0));
+    }
+    for _ in 0..lit {
+        result.push(color);
+    }
+}
+
+// Like the fire, but the flames are as high as the music is loud, and jump up on beats.
+fn render_audio_fire(t: f64, color_rgb: palette::Srgb, strands: &[usize], audio: &AudioFeatures) -> Vec<LedColor> {
+    let perlin = Perlin::default();
+    let on = LedColor::from_u8_rgb(color_rgb.into_format().into_raw());
+    let mut result = Vec::new();
+    for (i, strand) in strands.iter().enumerate() {
+        let noise = ((perlin.get([t, i as f64]) + 1.0) / 2.0) as f32;
+        let height = (audio.volume * (0.7 + 0.3 * noise) + 0.2 * audio.beat).min(1.0);
+        fill_strand(&mut result, *strand, (height * *strand as f32).round() as usize, on);
+    }
+    return result;
+}
+
+// A level meter on every strand, in the selected color with a red peak zone.
+fn render_vu(color_rgb: palette::Srgb, strands: &[usize], audio: &AudioFeatures) -> Vec<LedColor> {
+    let on = LedColor::from_u8_rgb(color_rgb.into_format().into_raw());
+    let luma = color_rgb.red.max(color_rgb.green).max(color_rgb.blue);
+    let peak = LedColor::from_u8_rgb([(luma * 255.0).round() as u8, 0, 0]);
+    let mut result = Vec::new();
+    for strand in strands {
+        let lit = (audio.volume * *strand as f32).round() as usize;
+        let red_from = (0.8 * *strand as f32).ceil() as usize;
+        // Counted from the bottom, like `fill_strand`.
+        for position in (0..*strand).rev() {
+            if position >= lit {
+                result.push(LedColor::from_u32_rgb(0x0));
+            } else if position >= red_from {
+                result.push(peak);
+            } else {
+                result.push(on);
+            }
+        }
+    }
+    return result;
+}
+
+// The frequency bands spread over the strands, lowest on the first one.
+fn render_spectrum(color_rgb: palette::Srgb, strands: &[usize], audio: &AudioFeatures) -> Vec<LedColor> {
+    let on = LedColor::from_u8_rgb(color_rgb.into_format().into_raw());
+    let mut result = Vec::new();
+    for (i, strand) in strands.iter().enumerate() {
+        // The loudest of the bands that fall onto this strand, or the
+        // nearest one if there are more strands than bands.
+        let bands = audio.bands.len();
+        let start = i * bands / strands.len();
+        let end = ((i + 1) * bands / strands.len()).max(start + 1).min(bands);
+        let level = audio.bands.get(start..end).unwrap_or(&[]).iter().copied().fold(0.0, f32::max);
+        fill_strand(&mut result, *strand, (level * *strand as f32).round() as usize, on);
+    }
+    return result;
+}
+
 fn render_static(_t: f64, color: palette::Srgb, strands: &Vec<usize>) -> Vec<LedColor> {
     // let mut cumsum = Vec::new();
     // for strand in &strands {
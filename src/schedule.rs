@@ -0,0 +1,523 @@
+// Rules that change the state of the lamp at certain times, e.g.
+// "turn on at sunset" or "turn off at 23:00".
+//
+// The rules are only evaluated here, applying their actions is
+// left to the server that owns the `Handle`.
+
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Duration;
+use chrono::Local;
+use chrono::NaiveDate;
+use chrono::TimeZone;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::firelight_api::Control;
+use crate::storage;
+use crate::sun;
+use crate::sun::Location;
+use crate::sun::SunEvent;
+
+/// One field of a cron expression, as a bitmask of the allowed values.
+#[derive(Clone, Debug)]
+struct CronField {
+    allowed: u64,
+    // Whether the field was given as `*`, which matters for the
+    // special handling of day-of-month and day-of-week.
+    any: bool,
+}
+
+impl CronField {
+    /// Parses lists of values, ranges and steps like `1,15`, `8-18`, `*/15` or `0-30/10`.
+    fn parse(text: &str, min: u32, max: u32) -> anyhow::Result<CronField> {
+        let mut allowed = 0u64;
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, Some(step.parse::<u32>()?)),
+                None => (part, None),
+            };
+            let (low, high) = if range == "*" {
+                (min, max)
+            } else if let Some((low, high)) = range.split_once('-') {
+                (low.parse::<u32>()?, high.parse::<u32>()?)
+            } else {
+                let value = range.parse::<u32>()?;
+                (value, if step.is_some() { max } else { value })
+            };
+            if low < min || high > max || low > high {
+                bail!("'{}' is outside of [{}, {}]", part, min, max);
+            }
+            let step = step.unwrap_or(1);
+            if step == 0 {
+                bail!("step must not be zero in '{}'", part);
+            }
+            for value in (low..=high).step_by(step as usize) {
+                allowed |= 1 << value;
+            }
+        }
+        return Ok(CronField {
+            allowed: allowed,
+            any: text == "*",
+        });
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        return self.allowed & (1 << value) != 0;
+    }
+
+    fn values(&self, min: u32, max: u32) -> impl Iterator<Item = u32> + '_ {
+        return (min..=max).filter(move |value| self.matches(*value));
+    }
+}
+
+/// A classic five-field cron expression: minute, hour, day of month,
+/// month and day of week (0 or 7 is Sunday).
+#[derive(Clone, Debug)]
+struct Cron {
+    minutes: CronField,
+    hours: CronField,
+    days: CronField,
+    months: CronField,
+    weekdays: CronField,
+}
+
+impl Cron {
+    fn parse(text: &str) -> anyhow::Result<Cron> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!("expected 5 fields (minute hour day month weekday)");
+        }
+        let mut weekdays = CronField::parse(fields[4], 0, 7)?;
+        if weekdays.matches(7) {
+            weekdays.allowed |= 1;
+        }
+        return Ok(Cron {
+            minutes: CronField::parse(fields[0], 0, 59)?,
+            hours: CronField::parse(fields[1], 0, 23)?,
+            days: CronField::parse(fields[2], 1, 31)?,
+            months: CronField::parse(fields[3], 1, 12)?,
+            weekdays: weekdays,
+        });
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months.matches(date.month()) {
+            return false;
+        }
+        let day = self.days.matches(date.day());
+        let weekday = self.weekdays.matches(date.weekday().num_days_from_sunday());
+        // Same as cron: if both are restricted, either one may match.
+        return match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+enum TriggerKind {
+    Cron(Cron),
+    Sun(SunEvent, Duration),
+}
+
+/// When a rule fires. Written either as a cron expression like
+/// `0 23 * * *`, or as `sunrise` or `sunset` with an optional offset
+/// like `sunset-30m` or `sunrise+1h15m`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Trigger {
+    text: String,
+    kind: TriggerKind,
+}
+
+fn parse_offset(text: &str) -> anyhow::Result<Duration> {
+    let (sign, mut rest) = match text.chars().next() {
+        None => return Ok(Duration::zero()),
+        Some('+') => (1, &text[1..]),
+        Some('-') => (-1, &text[1..]),
+        Some(_) => bail!("offset must start with '+' or '-'"),
+    };
+    let mut minutes = 0i64;
+    if let Some((hours, remainder)) = rest.split_once('h') {
+        minutes += 60 * hours.parse::<i64>()?;
+        rest = remainder;
+    }
+    if let Some(remainder) = rest.strip_suffix('m') {
+        minutes += remainder.parse::<i64>()?;
+    } else if !rest.is_empty() {
+        bail!("offsets are written like '+30m', '-1h' or '+1h30m'");
+    }
+    if minutes > 12 * 60 {
+        bail!("offsets must not be longer than 12 hours");
+    }
+    return Ok(Duration::minutes(sign * minutes));
+}
+
+impl Trigger {
+    pub fn parse(text: &str) -> anyhow::Result<Trigger> {
+        let text = text.trim();
+        let kind = if let Some(offset) = text.strip_prefix("sunrise") {
+            TriggerKind::Sun(SunEvent::Sunrise, parse_offset(offset)?)
+        } else if let Some(offset) = text.strip_prefix("sunset") {
+            TriggerKind::Sun(SunEvent::Sunset, parse_offset(offset)?)
+        } else {
+            TriggerKind::Cron(Cron::parse(text)?)
+        };
+        return Ok(Trigger {
+            text: text.to_string(),
+            kind: kind,
+        });
+    }
+
+    pub fn needs_location(&self) -> bool {
+        return matches!(self.kind, TriggerKind::Sun(..));
+    }
+
+    /// All times at which the trigger fires that belong to the given day.
+    fn times_on(&self, date: NaiveDate, location: Option<Location>) -> Vec<DateTime<Local>> {
+        return match &self.kind {
+            TriggerKind::Cron(cron) => {
+                if !cron.matches_date(date) {
+                    return Vec::new();
+                }
+                let mut times = Vec::new();
+                for hour in cron.hours.values(0, 23) {
+                    for minute in cron.minutes.values(0, 59) {
+                        // Times that are skipped by a daylight saving
+                        // transition just don't happen on that day.
+                        let naive = date.and_hms_opt(hour, minute, 0).unwrap();
+                        if let Some(time) = Local.from_local_datetime(&naive).earliest() {
+                            times.push(time);
+                        }
+                    }
+                }
+                times
+            }
+            TriggerKind::Sun(event, offset) => {
+                match location.and_then(|location| sun::sun_event(*event, date, location)) {
+                    Some(time) => vec![time.with_timezone(&Local) + *offset],
+                    None => Vec::new(),
+                }
+            }
+        };
+    }
+
+    /// Whether the trigger fired in the interval `(from, to]`.
+    pub fn fires_between(&self, from: DateTime<Local>, to: DateTime<Local>, location: Option<Location>) -> bool {
+        // After a long suspend or a clock jump, only catch up on the last day.
+        let from = std::cmp::max(from, to - Duration::days(1));
+        let mut date = from.date_naive().pred_opt().unwrap_or(NaiveDate::MIN);
+        let last = to.date_naive().succ_opt().unwrap_or(NaiveDate::MAX);
+        while date <= last {
+            if self.times_on(date, location).iter().any(|time| from < *time && *time <= to) {
+                return true;
+            }
+            date = match date.succ_opt() {
+                Some(date) => date,
+                None => break,
+            };
+        }
+        return false;
+    }
+
+    /// The next time after `after` at which the trigger fires,
+    /// looking at most one year ahead.
+    pub fn next_after(&self, after: DateTime<Local>, location: Option<Location>) -> Option<DateTime<Local>> {
+        let mut date = after.date_naive().pred_opt()?;
+        for _ in 0..368 {
+            let next = self.times_on(date, location).into_iter().filter(|time| *time > after).min();
+            if next.is_some() {
+                return next;
+            }
+            date = date.succ_opt()?;
+        }
+        return None;
+    }
+}
+
+impl TryFrom<String> for Trigger {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<Trigger> {
+        return Trigger::parse(&text).map_err(|e| anyhow!("invalid trigger '{}': {}", text, e));
+    }
+}
+
+impl From<Trigger> for String {
+    fn from(trigger: Trigger) -> String {
+        return trigger.text;
+    }
+}
+
+/// What happens when a rule fires.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Set the full state.
+    Control(Control),
+
+    /// Apply the preset with the given name.
+    Preset(String),
+
+    /// Turn the lamp off, keeping the rest of the state.
+    Off,
+}
+
+fn default_enabled() -> bool {
+    return true;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Rule {
+    /// Assigned by the `ScheduleStore`, ignored when adding a rule.
+    #[serde(default)]
+    pub id: u32,
+
+    /// Optional description for humans.
+    #[serde(default)]
+    pub name: String,
+
+    /// A cron expression like `0 23 * * *`, or `sunrise` or `sunset`
+    /// with an optional offset like `sunset-30m`.
+    #[schemars(with = "String")]
+    pub at: Trigger,
+
+    pub action: Action,
+
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// The list of schedule rules, persisted as a JSON file.
+pub struct ScheduleStore {
+    // If `None`, rules are only kept in memory.
+    path: Option<PathBuf>,
+    location: Option<Location>,
+    rules: Vec<Rule>,
+}
+
+impl ScheduleStore {
+    /// Loads the rules from `path`. A missing file is not an error, it
+    /// will be created when the first rule is added. Rules relative to
+    /// sunrise or sunset can only be used if `location` is known.
+    pub fn open(path: Option<PathBuf>, location: Option<Location>) -> anyhow::Result<ScheduleStore> {
+        let rules: Vec<Rule> = match &path {
+            Some(path) => storage::load_json(path)?.unwrap_or_default(),
+            None => Vec::new(),
+        };
+        for rule in &rules {
+            if let Action::Control(control) = &rule.action {
+                control.validate().map_err(|e| anyhow!("invalid rule {}: {}", rule.id, e))?;
+            }
+        }
+        return Ok(ScheduleStore {
+            path: path,
+            location: location,
+            rules: rules,
+        });
+    }
+
+    pub fn list(&self) -> &[Rule] {
+        return &self.rules;
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Rule> {
+        return self.rules.iter().find(|rule| rule.id == id);
+    }
+
+    /// Adds a new rule and returns it with its assigned id.
+    pub fn add(&mut self, mut rule: Rule) -> anyhow::Result<Rule> {
+        self.validate(&rule)?;
+        rule.id = self.rules.iter().map(|rule| rule.id).max().unwrap_or(0) + 1;
+        let mut rules = self.rules.clone();
+        rules.push(rule.clone());
+        self.save(rules)?;
+        return Ok(rule);
+    }
+
+    /// Replaces the rule with the given id. Returns `None` if there is no such rule.
+    pub fn replace(&mut self, id: u32, mut rule: Rule) -> anyhow::Result<Option<Rule>> {
+        self.validate(&rule)?;
+        rule.id = id;
+        let mut rules = self.rules.clone();
+        match rules.iter_mut().find(|existing| existing.id == id) {
+            Some(existing) => *existing = rule.clone(),
+            None => return Ok(None),
+        }
+        self.save(rules)?;
+        return Ok(Some(rule));
+    }
+
+    /// Returns `false` if there was no rule with that id.
+    pub fn remove(&mut self, id: u32) -> anyhow::Result<bool> {
+        let mut rules = self.rules.clone();
+        rules.retain(|rule| rule.id != id);
+        if rules.len() == self.rules.len() {
+            return Ok(false);
+        }
+        self.save(rules)?;
+        return Ok(true);
+    }
+
+    /// All enabled rules that fired in the interval `(from, to]`.
+    pub fn due(&self, from: DateTime<Local>, to: DateTime<Local>) -> Vec<Rule> {
+        return self
+            .rules
+            .iter()
+            .filter(|rule| rule.enabled && rule.at.fires_between(from, to, self.location))
+            .cloned()
+            .collect();
+    }
+
+    pub fn next(&self, rule: &Rule, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        if !rule.enabled {
+            return None;
+        }
+        return rule.at.next_after(after, self.location);
+    }
+
+    /// Rejects rules that can't fire, because the location is unknown.
+    pub fn validate(&self, rule: &Rule) -> anyhow::Result<()> {
+        if rule.at.needs_location() && self.location.is_none() {
+            bail!("rules relative to sunrise or sunset need a configured location");
+        }
+        return Ok(());
+    }
+
+    /// Stores `rules` and then replaces the list, so that the list
+    /// stays as it is in the file if storing fails.
+    fn save(&mut self, rules: Vec<Rule>) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            storage::save_json(path, &rules).map_err(|e| anyhow!("couldn't store the schedule: {}", e))?;
+        }
+        self.rules = rules;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(text: &str, min: u32, max: u32) -> Vec<u32> {
+        return CronField::parse(text, min, max).unwrap().values(min, max).collect();
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        return NaiveDate::from_ymd_opt(year, month, day).unwrap();
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        return Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap();
+    }
+
+    #[test]
+    fn cron_field_lists_ranges_and_steps() {
+        assert_eq!(values("*", 0, 6), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(values("1,15", 0, 59), vec![1, 15]);
+        assert_eq!(values("8-11", 0, 23), vec![8, 9, 10, 11]);
+        assert_eq!(values("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(values("0-30/10", 0, 59), vec![0, 10, 20, 30]);
+        assert_eq!(values("5/20", 0, 59), vec![5, 25, 45]);
+        assert_eq!(values("1-3,20-21", 1, 31), vec![1, 2, 3, 20, 21]);
+    }
+
+    #[test]
+    fn cron_field_rejects_invalid_values() {
+        for text in ["60", "5-3", "*/0", "a", "", "1-", "-1"] {
+            assert!(CronField::parse(text, 0, 59).is_err(), "'{}' should be rejected", text);
+        }
+        assert!(CronField::parse("0", 1, 31).is_err());
+    }
+
+    #[test]
+    fn cron_needs_five_fields() {
+        assert!(Cron::parse("0 23 * * *").is_ok());
+        assert!(Cron::parse("0 23 * *").is_err());
+        assert!(Cron::parse("0 0 23 * * *").is_err());
+    }
+
+    #[test]
+    fn cron_sunday_is_zero_or_seven() {
+        let sunday = date(2024, 1, 14);
+        assert!(Cron::parse("0 0 * * 0").unwrap().matches_date(sunday));
+        assert!(Cron::parse("0 0 * * 7").unwrap().matches_date(sunday));
+        assert!(!Cron::parse("0 0 * * 1-5").unwrap().matches_date(sunday));
+    }
+
+    #[test]
+    fn cron_day_or_weekday() {
+        // Like cron, a restricted day of month and day of week match either way.
+        let cron = Cron::parse("0 0 13 * 5").unwrap();
+        assert!(cron.matches_date(date(2024, 9, 13)));
+        assert!(cron.matches_date(date(2024, 9, 6)));
+        assert!(cron.matches_date(date(2024, 8, 13)));
+        assert!(!cron.matches_date(date(2024, 9, 12)));
+        // With only one of them restricted, that one has to match.
+        let cron = Cron::parse("0 0 13 2 *").unwrap();
+        assert!(cron.matches_date(date(2024, 2, 13)));
+        assert!(!cron.matches_date(date(2024, 3, 13)));
+        assert!(!cron.matches_date(date(2024, 2, 6)));
+    }
+
+    #[test]
+    fn trigger_offsets() {
+        let offset = |text: &str| match Trigger::parse(text).unwrap().kind {
+            TriggerKind::Sun(_, offset) => offset.num_minutes(),
+            TriggerKind::Cron(_) => panic!("'{}' should be a sun trigger", text),
+        };
+        assert_eq!(offset("sunrise"), 0);
+        assert_eq!(offset("sunset-30m"), -30);
+        assert_eq!(offset("sunrise+1h15m"), 75);
+        assert_eq!(offset("sunset-2h"), -120);
+        for text in ["sunset30m", "sunrise+2x", "sunset+13h", "sunrise+h"] {
+            assert!(Trigger::parse(text).is_err(), "'{}' should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn trigger_errors_name_the_trigger() {
+        let error = serde_json::from_str::<Trigger>("\"60 * * * *\"").unwrap_err().to_string();
+        assert!(error.contains("invalid trigger '60 * * * *'"), "{}", error);
+        let trigger: Trigger = serde_json::from_str("\" sunset-30m \"").unwrap();
+        assert_eq!(String::from(trigger), "sunset-30m");
+    }
+
+    #[test]
+    fn cron_fires_between() {
+        let trigger = Trigger::parse("30 7 * * *").unwrap();
+        assert!(trigger.fires_between(local(2024, 1, 10, 7, 0), local(2024, 1, 10, 7, 31), None));
+        // The interval excludes its start.
+        assert!(!trigger.fires_between(local(2024, 1, 10, 7, 30), local(2024, 1, 10, 8, 0), None));
+        assert!(trigger.fires_between(local(2024, 1, 10, 7, 29), local(2024, 1, 10, 7, 30), None));
+        assert!(!trigger.fires_between(local(2024, 1, 10, 8, 0), local(2024, 1, 11, 7, 0), None));
+    }
+
+    #[test]
+    fn cron_next_after() {
+        let trigger = Trigger::parse("0 23 * * 1-5").unwrap();
+        // From Friday night to Monday.
+        assert_eq!(trigger.next_after(local(2024, 1, 12, 23, 30), None), Some(local(2024, 1, 15, 23, 0)));
+        assert_eq!(trigger.next_after(local(2024, 1, 15, 22, 59), None), Some(local(2024, 1, 15, 23, 0)));
+        // February 30th never happens.
+        assert_eq!(Trigger::parse("0 0 30 2 *").unwrap().next_after(local(2024, 1, 1, 0, 0), None), None);
+    }
+
+    #[test]
+    fn sun_triggers_need_a_location() {
+        let trigger = Trigger::parse("sunset-30m").unwrap();
+        assert!(trigger.needs_location());
+        assert_eq!(trigger.next_after(local(2024, 1, 10, 12, 0), None), None);
+
+        let berlin = Location { latitude: 52.52, longitude: 13.405 };
+        let next = trigger.next_after(local(2024, 1, 10, 12, 0), Some(berlin)).unwrap();
+        let sunset = sun::sun_event(SunEvent::Sunset, date(2024, 1, 10), berlin).unwrap();
+        assert_eq!(next, sunset.with_timezone(&Local) - Duration::minutes(30));
+    }
+}
@@ -0,0 +1,25 @@
+// Persistence for state that is managed through the REST api,
+// like presets and schedules.
+
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Reads a JSON file. Returns `None` if the file doesn't exist yet.
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = std::fs::File::open(path)?;
+    return Ok(Some(serde_json::from_reader(std::io::BufReader::new(file))?));
+}
+
+/// Writes a JSON file. The data is written to a temporary file first,
+/// so a crash or a full disk can't leave behind a truncated file.
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&tmp, path)?;
+    return Ok(());
+}
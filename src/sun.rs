@@ -0,0 +1,149 @@
+// Sunrise and sunset times, computed offline from the coordinates
+// of the lamp so that schedules don't need network access.
+//
+// This uses the algorithm from the "Almanac for Computers" (1990) published
+// by the US Naval Observatory, which is accurate to a few minutes for
+// latitudes between the polar circles.
+
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Duration;
+use chrono::NaiveDate;
+use chrono::TimeZone;
+use chrono::Utc;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Location {
+    /// In degrees, positive towards north.
+    pub latitude: f64,
+
+    /// In degrees, positive towards east.
+    pub longitude: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+// Official zenith for sunrise and sunset, including atmospheric
+// refraction and the radius of the solar disk.
+const ZENITH: f64 = 90.833;
+
+fn sin(degrees: f64) -> f64 {
+    return degrees.to_radians().sin();
+}
+
+fn cos(degrees: f64) -> f64 {
+    return degrees.to_radians().cos();
+}
+
+/// Returns the time of sunrise or sunset on the given date, or `None`
+/// if the sun doesn't rise or set on that day (polar day or night).
+/// The date is interpreted as a calendar day at the given location.
+pub fn sun_event(event: SunEvent, date: NaiveDate, location: Location) -> Option<DateTime<Utc>> {
+    let day_of_year = date.ordinal() as f64;
+    let longitude_hour = location.longitude / 15.0;
+    let approximate_hour = match event {
+        SunEvent::Sunrise => 6.0,
+        SunEvent::Sunset => 18.0,
+    };
+    let t = day_of_year + (approximate_hour - longitude_hour) / 24.0;
+
+    // Mean anomaly and true longitude of the sun.
+    let mean_anomaly = 0.9856 * t - 3.289;
+    let true_longitude = (mean_anomaly
+        + 1.916 * sin(mean_anomaly)
+        + 0.020 * sin(2.0 * mean_anomaly)
+        + 282.634)
+        .rem_euclid(360.0);
+
+    // Right ascension, moved into the same quadrant as the true longitude.
+    let mut right_ascension = (0.91764 * true_longitude.to_radians().tan())
+        .atan()
+        .to_degrees()
+        .rem_euclid(360.0);
+    right_ascension += (true_longitude / 90.0).floor() * 90.0 - (right_ascension / 90.0).floor() * 90.0;
+    let right_ascension_hours = right_ascension / 15.0;
+
+    // Declination and local hour angle.
+    let sin_declination = 0.39782 * sin(true_longitude);
+    let cos_declination = sin_declination.asin().cos();
+    let cos_hour_angle = (cos(ZENITH) - sin_declination * sin(location.latitude))
+        / (cos_declination * cos(location.latitude));
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = match event {
+        SunEvent::Sunrise => 360.0 - cos_hour_angle.acos().to_degrees(),
+        SunEvent::Sunset => cos_hour_angle.acos().to_degrees(),
+    } / 15.0;
+
+    let local_mean_time = hour_angle + right_ascension_hours - 0.06571 * t - 6.622;
+    let universal_hours = (local_mean_time - longitude_hour).rem_euclid(24.0);
+
+    // The result is a time of day in UTC, which might belong to the
+    // previous or next UTC day. Pick the one closest to local noon.
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    let local_noon = midnight + Duration::minutes(((12.0 - longitude_hour) * 60.0) as i64);
+    let mut result = midnight + Duration::seconds((universal_hours * 3600.0) as i64);
+    if result - local_noon > Duration::hours(12) {
+        result -= Duration::days(1);
+    } else if local_noon - result > Duration::hours(12) {
+        result += Duration::days(1);
+    }
+    return Some(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BERLIN: Location = Location { latitude: 52.52, longitude: 13.405 };
+    const SAN_FRANCISCO: Location = Location { latitude: 37.77, longitude: -122.42 };
+    const SYDNEY: Location = Location { latitude: -33.87, longitude: 151.21 };
+    const TROMSO: Location = Location { latitude: 69.65, longitude: 18.96 };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        return NaiveDate::from_ymd_opt(year, month, day).unwrap();
+    }
+
+    /// Checks the result against published times, which the algorithm
+    /// should match within a few minutes.
+    fn assert_near(actual: Option<DateTime<Utc>>, expected: &str) {
+        let expected = DateTime::parse_from_rfc3339(expected).unwrap().with_timezone(&Utc);
+        let actual = actual.expect("the sun should rise and set");
+        assert!(
+            (actual - expected).num_minutes().abs() <= 3,
+            "expected about {}, got {}", expected, actual
+        );
+    }
+
+    #[test]
+    fn summer_and_winter_solstice() {
+        assert_near(sun_event(SunEvent::Sunrise, date(2024, 6, 21), BERLIN), "2024-06-21T02:43:00Z");
+        assert_near(sun_event(SunEvent::Sunset, date(2024, 6, 21), BERLIN), "2024-06-21T19:33:00Z");
+        assert_near(sun_event(SunEvent::Sunrise, date(2024, 12, 21), BERLIN), "2024-12-21T07:15:00Z");
+        assert_near(sun_event(SunEvent::Sunset, date(2024, 12, 21), BERLIN), "2024-12-21T14:54:00Z");
+    }
+
+    #[test]
+    fn events_on_another_utc_day() {
+        // In the evening in California, it's already the next day in UTC.
+        assert_near(sun_event(SunEvent::Sunrise, date(2024, 6, 21), SAN_FRANCISCO), "2024-06-21T12:48:00Z");
+        assert_near(sun_event(SunEvent::Sunset, date(2024, 6, 21), SAN_FRANCISCO), "2024-06-22T03:35:00Z");
+        // In the morning in Australia, it's still the previous day in UTC.
+        assert_near(sun_event(SunEvent::Sunrise, date(2024, 6, 21), SYDNEY), "2024-06-20T21:00:00Z");
+        assert_near(sun_event(SunEvent::Sunset, date(2024, 6, 21), SYDNEY), "2024-06-21T06:54:00Z");
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        assert_eq!(sun_event(SunEvent::Sunrise, date(2024, 6, 21), TROMSO), None);
+        assert_eq!(sun_event(SunEvent::Sunset, date(2024, 6, 21), TROMSO), None);
+        assert_eq!(sun_event(SunEvent::Sunrise, date(2024, 12, 21), TROMSO), None);
+        assert_eq!(sun_event(SunEvent::Sunset, date(2024, 12, 21), TROMSO), None);
+        assert!(sun_event(SunEvent::Sunrise, date(2024, 3, 20), TROMSO).is_some());
+    }
+}
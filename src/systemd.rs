@@ -0,0 +1,71 @@
+// Integration with systemd: socket activation, readiness notifications
+// and the service watchdog. Without systemd, all of this does nothing.
+
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+
+use anyhow::bail;
+use sd_notify::NotifyState;
+
+/// The socket passed by systemd, if the service was socket activated.
+fn listen_fd() -> anyhow::Result<Option<RawFd>> {
+    let mut fds = sd_notify::listen_fds()?;
+    let fd = fds.next();
+    if fds.next().is_some() {
+        bail!("expected a single socket from systemd");
+    }
+    return Ok(fd);
+}
+
+/// Returns the listening unix socket passed by systemd, if any.
+pub fn unix_listener() -> anyhow::Result<Option<UnixListener>> {
+    // Safe as long as the socket unit is set up with `ListenStream`
+    // for a path, since the fd is owned by nobody else in this process.
+    return Ok(listen_fd()?.map(|fd| unsafe { UnixListener::from_raw_fd(fd) }));
+}
+
+/// Returns the listening TCP socket passed by systemd, if any.
+pub fn tcp_listener() -> anyhow::Result<Option<TcpListener>> {
+    // See above, but for `ListenStream` with a port or address.
+    return Ok(listen_fd()?.map(|fd| unsafe { TcpListener::from_raw_fd(fd) }));
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        log::warn!("couldn't notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd that startup is complete.
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Tells systemd that the configuration is being reloaded. Must
+/// be followed by `notify_ready` once the reload is complete.
+pub fn notify_reloading() {
+    notify(NotifyState::Reloading);
+}
+
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+/// If the service has `WatchdogSec` set, pings the watchdog from a new thread
+/// twice per interval. Before every ping, `alive` is called, which should
+/// block if the service is stuck, so that systemd restarts it.
+pub fn spawn_watchdog<F: Fn() + Send + 'static>(alive: F) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = Duration::from_micros(usec / 2);
+    std::thread::spawn(move || loop {
+        alive();
+        notify(NotifyState::Watchdog);
+        std::thread::sleep(interval);
+    });
+}
@@ -0,0 +1,507 @@
+// A subset of the JSON API and the UDP realtime protocols of WLED, so that
+// apps and integrations written for WLED can control a firelight lamp.
+// The lamp presents itself as a single segment that covers the whole strip.
+// See https://kno.wled.ge/interfaces/json-api/ and
+// https://kno.wled.ge/interfaces/udp-realtime/ for the original protocols.
+
+use std::net::UdpSocket;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use palette::FromColor;
+use palette::Pixel;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::firelight_api::Control;
+use crate::firelight_api::Effect;
+use crate::firelight_api::Status;
+use crate::metrics;
+use crate::presets::Preset;
+use crate::renderer::ExternalInput;
+
+/// The WLED version whose API is implemented. Clients use it
+/// to decide which features they can use.
+const WLED_VERSION: &str = "0.14.0";
+const WLED_BUILD: u32 = 2310130;
+
+/// Names of the effects, indexed by their WLED effect id. Apps
+/// expect id 0 to be a solid color.
+pub fn effect_names() -> Vec<String> {
+    return Effect::all()
+        .iter()
+        .map(|effect| match effect {
+            Effect::Static => "Solid".to_string(),
+            Effect::Fire => "Fire".to_string(),
+            Effect::AudioFire => "Fire (Sound)".to_string(),
+            Effect::Vu => "VU Meter".to_string(),
+            Effect::Spectrum => "Spectrum".to_string(),
+        })
+        .collect();
+}
+
+fn effect_id(effect: Effect) -> usize {
+    return Effect::all().iter().position(|e| *e == effect).unwrap_or(0);
+}
+
+/// Maps the effect speed of WLED in [0, 255] to ours in [0.0, 10.0], such
+/// that the middle of the range, which is WLED's default, is normal speed.
+fn speed_from_sx(sx: u8) -> f32 {
+    let sx = sx as f32;
+    if sx <= 128.0 {
+        return sx / 128.0;
+    }
+    return 1.0 + (sx - 128.0) / 127.0 * 9.0;
+}
+
+fn sx_from_speed(speed: f32) -> u8 {
+    let sx = if speed <= 1.0 {
+        speed * 128.0
+    } else {
+        128.0 + (speed - 1.0) / 9.0 * 127.0
+    };
+    return sx.round().clamp(0.0, 255.0) as u8;
+}
+
+/// The fully saturated color with the given hue and saturation, since
+/// the brightness is a separate property in both APIs.
+fn rgb_from_hs(color_hs: (f32, f32)) -> [u8; 3] {
+    let hsv = palette::Hsv::new(color_hs.0, color_hs.1 / 100.0, 1.0);
+    return palette::Srgb::from_color(hsv).into_format().into_raw();
+}
+
+fn hs_from_rgb(rgb: [u8; 3]) -> (f32, f32) {
+    let srgb: palette::Srgb = palette::Srgb::from_raw(&rgb).into_format();
+    let hsv = palette::Hsv::from_color(srgb);
+    return (hsv.hue.to_positive_degrees(), hsv.saturation * 100.0);
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Segment {
+    pub id: u8,
+    pub start: usize,
+    pub stop: usize,
+    pub len: usize,
+    pub on: bool,
+    pub bri: u8,
+    /// The primary, secondary and tertiary color. Only the primary one is used.
+    pub col: Vec<[u8; 3]>,
+    /// Index into the list of effects.
+    pub fx: usize,
+    /// Effect speed.
+    pub sx: u8,
+    /// Effect intensity, which isn't used.
+    pub ix: u8,
+    pub pal: u8,
+    pub sel: bool,
+    pub rev: bool,
+    pub mi: bool,
+}
+
+/// The sleep timer, which is called nightlight by WLED.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Nightlight {
+    pub on: bool,
+    /// Duration in minutes.
+    pub dur: u8,
+    /// Always 1, i.e. fading.
+    pub mode: u8,
+    /// Target brightness, which is always 0.
+    pub tbri: u8,
+    /// Remaining seconds, or -1 if not running.
+    pub rem: i64,
+}
+
+/// The response of `GET /json/state`.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct State {
+    pub on: bool,
+    pub bri: u8,
+    /// In units of 100ms. Changes are applied immediately.
+    pub transition: u8,
+    /// The id of the active preset, or -1.
+    pub ps: i64,
+    /// Playlists aren't supported, so this is always -1.
+    pub pl: i64,
+    pub nl: Nightlight,
+    /// Whether realtime data may be overridden, which isn't supported.
+    pub lor: u8,
+    pub mainseg: u8,
+    pub seg: Vec<Segment>,
+}
+
+impl State {
+    /// `preset` is the id of the active preset.
+    pub fn new(status: &Status, leds: usize, preset: Option<u32>, now: SystemTime) -> State {
+        let control = status.control;
+        let remaining = status.sleep_until.map(|until| until.duration_since(now).unwrap_or_default());
+        return State {
+            on: control.on,
+            bri: control.brightness,
+            transition: 0,
+            ps: preset.map_or(-1, |id| id as i64),
+            pl: -1,
+            nl: Nightlight {
+                on: remaining.is_some(),
+                dur: remaining.map_or(0, |remaining| (remaining.as_secs() / 60).min(255) as u8),
+                mode: 1,
+                tbri: 0,
+                rem: remaining.map_or(-1, |remaining| remaining.as_secs() as i64),
+            },
+            lor: 0,
+            mainseg: 0,
+            seg: vec![Segment {
+                id: 0,
+                start: 0,
+                stop: leds,
+                len: leds,
+                on: true,
+                bri: 255,
+                col: vec![rgb_from_hs(control.color_hs), [0, 0, 0], [0, 0, 0]],
+                fx: effect_id(control.effect),
+                sx: sx_from_speed(control.speed),
+                ix: 128,
+                pal: 0,
+                sel: true,
+                rev: false,
+                mi: false,
+            }],
+        };
+    }
+}
+
+/// A boolean, or `"t"` to toggle.
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum Switch {
+    Set(bool),
+    Toggle(String),
+}
+
+impl Switch {
+    fn apply(&self, current: bool) -> anyhow::Result<bool> {
+        return match self {
+            Switch::Set(value) => Ok(*value),
+            Switch::Toggle(text) if text == "t" => Ok(!current),
+            Switch::Toggle(text) => Err(anyhow!("invalid value '{}', expected a boolean or \"t\"", text)),
+        };
+    }
+}
+
+/// A color as `[r, g, b]`, `[r, g, b, w]` or a hex string like `"FF8000"`.
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum ColorValue {
+    Channels(Vec<u8>),
+    Hex(String),
+}
+
+impl ColorValue {
+    fn rgb(&self) -> anyhow::Result<[u8; 3]> {
+        return match self {
+            Self::Channels(channels) if channels.len() >= 3 => Ok([channels[0], channels[1], channels[2]]),
+            Self::Channels(_) => Err(anyhow!("colors need at least three channels")),
+            Self::Hex(text) => {
+                let value = u32::from_str_radix(text.trim_start_matches('#'), 16)
+                    .map_err(|_| anyhow!("invalid hex color '{}'", text))?;
+                let [_, r, g, b] = value.to_be_bytes();
+                Ok([r, g, b])
+            },
+        };
+    }
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct SegmentUpdate {
+    /// Only segment 0 exists, updates of other segments are ignored.
+    pub id: Option<usize>,
+    pub on: Option<Switch>,
+    pub col: Option<Vec<ColorValue>>,
+    pub fx: Option<usize>,
+    pub sx: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum SegmentUpdates {
+    One(SegmentUpdate),
+    Many(Vec<SegmentUpdate>),
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct NightlightUpdate {
+    pub on: Option<bool>,
+    /// Duration in minutes.
+    pub dur: Option<u8>,
+}
+
+/// The body of `POST /json/state`. All fields are optional, and
+/// fields that aren't supported are ignored like by WLED.
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct StateUpdate {
+    pub on: Option<Switch>,
+    pub bri: Option<u8>,
+    /// Applies the preset with this id.
+    pub ps: Option<u32>,
+    pub nl: Option<NightlightUpdate>,
+    /// `false` ends realtime mode, i.e. shows rendered frames again.
+    pub live: Option<bool>,
+    pub seg: Option<SegmentUpdates>,
+    /// Respond with the new state instead of `{"success": true}`.
+    #[serde(default)]
+    pub v: bool,
+}
+
+impl StateUpdate {
+    /// The state after applying the changes to on, brightness and the segment.
+    pub fn control(&self, current: Control) -> anyhow::Result<Control> {
+        let mut control = current;
+        if let Some(on) = &self.on {
+            control.on = on.apply(control.on)?;
+        }
+        if let Some(bri) = self.bri {
+            control.brightness = bri;
+        }
+        let segments = match &self.seg {
+            None => Vec::new(),
+            Some(SegmentUpdates::One(segment)) => vec![segment],
+            Some(SegmentUpdates::Many(segments)) => segments.iter().collect(),
+        };
+        for (index, segment) in segments.into_iter().enumerate() {
+            if segment.id.unwrap_or(index) != 0 {
+                continue;
+            }
+            if let Some(on) = &segment.on {
+                control.on = on.apply(control.on)?;
+            }
+            if let Some(color) = segment.col.as_ref().and_then(|colors| colors.first()) {
+                // Black has no hue or saturation and would otherwise show as white,
+                // so it turns the lamp off and keeps the current color.
+                match color.rgb()? {
+                    [0, 0, 0] => control.on = false,
+                    rgb => control.color_hs = hs_from_rgb(rgb),
+                }
+            }
+            if let Some(fx) = segment.fx {
+                control.effect = *Effect::all().get(fx).ok_or_else(|| anyhow!("unknown effect id {}", fx))?;
+            }
+            if let Some(sx) = segment.sx {
+                control.speed = speed_from_sx(sx);
+            }
+        }
+        return Ok(control);
+    }
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct LedsInfo {
+    pub count: usize,
+    /// Estimated current in milliamps.
+    pub pwr: u32,
+    pub fps: u32,
+    /// No current limit is configured.
+    pub maxpwr: u32,
+    pub maxseg: u32,
+    /// Light capabilities of the segments, where 1 means RGB.
+    pub seglc: Vec<u8>,
+    pub lc: u8,
+    pub rgbw: bool,
+    pub wv: u8,
+    pub cct: u8,
+}
+
+/// The response of `GET /json/info`.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Info {
+    pub ver: String,
+    pub vid: u32,
+    pub leds: LedsInfo,
+    #[serde(rename = "str")]
+    pub sync_toggle: bool,
+    pub name: String,
+    /// Port of the UDP realtime receiver, or 0 if it's disabled.
+    pub udpport: u16,
+    /// Whether realtime or other external frames are shown.
+    pub live: bool,
+    /// The source of the external frames.
+    pub lm: String,
+    pub lip: String,
+    /// Websockets aren't supported.
+    pub ws: i32,
+    pub fxcount: usize,
+    pub palcount: usize,
+    pub arch: String,
+    /// The version of firelight.
+    pub core: String,
+    pub brand: String,
+    pub product: String,
+    pub mac: String,
+    pub ip: String,
+    /// Seconds since the server started.
+    pub uptime: u64,
+}
+
+impl Info {
+    pub fn new(name: &str, version: &str, status: &Status, colors: &[u32], udp_port: u16, uptime: Duration) -> Info {
+        let source = status.external_source.clone().unwrap_or_default();
+        // The source is e.g. "wled-udp from 10.0.0.5".
+        let address = source.split_once(" from ").map(|(_, address)| address.to_string()).unwrap_or_default();
+        return Info {
+            ver: WLED_VERSION.to_string(),
+            vid: WLED_BUILD,
+            leds: LedsInfo {
+                count: colors.len(),
+                pwr: (metrics::estimated_watts(colors) / metrics::VOLTS * 1000.0).round() as u32,
+                fps: status.render.fps.round() as u32,
+                maxpwr: 0,
+                maxseg: 1,
+                seglc: vec![1],
+                lc: 1,
+                rgbw: false,
+                wv: 0,
+                cct: 0,
+            },
+            sync_toggle: false,
+            name: name.to_string(),
+            udpport: udp_port,
+            live: status.external_source.is_some(),
+            lm: source,
+            lip: address,
+            ws: -1,
+            fxcount: Effect::all().len(),
+            palcount: 1,
+            arch: std::env::consts::ARCH.to_string(),
+            core: version.to_string(),
+            brand: "WLED".to_string(),
+            product: "firelight".to_string(),
+            mac: mac_address().unwrap_or_default(),
+            ip: String::new(),
+            uptime: uptime.as_secs(),
+        };
+    }
+}
+
+/// The response of `GET /json`.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct Full {
+    pub state: State,
+    pub info: Info,
+    pub effects: Vec<String>,
+    pub palettes: Vec<String>,
+}
+
+/// Palettes aren't supported, so this is the list with the default one.
+pub fn palette_names() -> Vec<String> {
+    return vec!["Default".to_string()];
+}
+
+/// The presets in the format of WLED's `presets.json`, keyed by their id,
+/// which starts at 1 like in WLED. Id 0 is always empty.
+pub fn presets_json(presets: &[Preset]) -> serde_json::Value {
+    let mut result = serde_json::Map::new();
+    result.insert("0".to_string(), serde_json::json!({}));
+    for preset in presets {
+        let control = preset.control;
+        result.insert(preset.id.to_string(), serde_json::json!({
+            "n": preset.name,
+            "on": control.on,
+            "bri": control.brightness,
+            "seg": [{
+                "id": 0,
+                "col": [rgb_from_hs(control.color_hs)],
+                "fx": effect_id(control.effect),
+                "sx": sx_from_speed(control.speed),
+            }],
+        }));
+    }
+    return serde_json::Value::Object(result);
+}
+
+/// The MAC address of the first network interface, as 12 hex digits. Some
+/// clients use it to tell lamps apart.
+fn mac_address() -> Option<String> {
+    let mut interfaces: Vec<_> = std::fs::read_dir("/sys/class/net").ok()?.flatten().collect();
+    interfaces.sort_by_key(|entry| entry.file_name());
+    return interfaces
+        .iter()
+        .filter(|entry| entry.file_name() != "lo")
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("address")).ok())
+        .map(|address| address.trim().replace(':', ""))
+        .find(|address| address.len() == 12 && address != "000000000000");
+}
+
+/// Colors received in a realtime packet.
+#[derive(Debug, PartialEq)]
+pub struct Realtime {
+    /// How long the colors are shown after the last packet. Zero
+    /// means that the renderer should take over right away.
+    pub timeout: Duration,
+    /// Runs of consecutive LEDs, each with the index of its first LED.
+    pub runs: Vec<(usize, Vec<u32>)>,
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    return ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
+}
+
+/// Parses a packet of one of the realtime protocols WARLS, DRGB, DRGBW
+/// or DNRGB. Returns `None` for other packets, like WLED notifications.
+pub fn parse_realtime(packet: &[u8]) -> Option<Realtime> {
+    const WARLS: u8 = 1;
+    const DRGB: u8 = 2;
+    const DRGBW: u8 = 3;
+    const DNRGB: u8 = 4;
+    if packet.len() < 2 {
+        return None;
+    }
+    let timeout = match packet[1] {
+        // WLED stays in realtime mode until another mode is selected.
+        255 => Duration::MAX,
+        seconds => Duration::from_secs(seconds as u64),
+    };
+    let runs = match packet[0] {
+        WARLS => packet[2..]
+            .chunks_exact(4)
+            .map(|led| (led[0] as usize, vec![rgb(led[1], led[2], led[3])]))
+            .collect(),
+        DRGB => vec![(0, packet[2..].chunks_exact(3).map(|led| rgb(led[0], led[1], led[2])).collect())],
+        DRGBW => {
+            // There are no white LEDs, so the white channel is mixed in.
+            let colors = packet[2..]
+                .chunks_exact(4)
+                .map(|led| rgb(led[0].saturating_add(led[3]), led[1].saturating_add(led[3]), led[2].saturating_add(led[3])))
+                .collect();
+            vec![(0, colors)]
+        },
+        DNRGB if packet.len() >= 4 => {
+            let start = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+            vec![(start, packet[4..].chunks_exact(3).map(|led| rgb(led[0], led[1], led[2])).collect())]
+        },
+        _ => return None,
+    };
+    return Some(Realtime { timeout: timeout, runs: runs });
+}
+
+/// Receives realtime packets on `socket` until an error occurs, and shows
+/// their colors instead of the rendered frames.
+pub fn receive(socket: UdpSocket, input: ExternalInput) -> anyhow::Result<()> {
+    log::info!("receiving WLED realtime packets on {}", socket.local_addr()?);
+    let mut buffer = [0u8; 1500];
+    loop {
+        let (len, sender) = socket.recv_from(&mut buffer)?;
+        let packet = match parse_realtime(&buffer[..len]) {
+            Some(packet) => packet,
+            None => continue,
+        };
+        let source = format!("wled-udp from {}", sender.ip());
+        if packet.timeout.is_zero() {
+            input.release(&source);
+            continue;
+        }
+        for (offset, colors) in &packet.runs {
+            input.update(&source, *offset, colors, packet.timeout);
+        }
+    }
+}